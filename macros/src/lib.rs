@@ -0,0 +1,467 @@
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{
+    ext::IdentExt, parse_macro_input, spanned::Spanned, Attribute, Data, DeriveInput, Error,
+    Field, Fields as StructFields, GenericArgument, Lit, LitStr, Meta, MetaList, NestedMeta,
+    PathArguments, Result, Type,
+};
+
+/// generates a typed `aws_rust::query::Field` constant for every serialized field, named
+/// after the field in upper case, e.g. `Todo::CREATED_AT`
+#[proc_macro_derive(Fields)]
+pub fn derive_fields(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_fields(&input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+/// implements `aws_rust::database::Model` from attributes:
+///
+/// - `#[model(collection = "todos")]` on the struct names the collection, with optional
+///   `soft_delete` and `audit = false` flags
+/// - `#[index]` on a field adds an ascending index, `#[index(order = -1)]` a descending one
+/// - `#[index(unique)]` makes it unique, among documents that aren't deleted on
+///   `soft_delete` models, and `#[index(ttl = "30d")]` expires documents that
+///   long after the field's date, in `s`, `m`, `h` or `d`
+/// - `#[index(sparse)]` leaves documents without the field out of the index
+/// - fields sharing `#[index(compound = "name")]` form one index, in field order
+/// - `#[index(text)]` fields form the collection's text index
+///
+/// fields of type `Ref<T>`, `Vec<Ref<T>>` or `Option<Ref<T>>` are declared as relations
+/// to `T`, so they can be populated
+#[proc_macro_derive(Model, attributes(model, index))]
+pub fn derive_model(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_model(&input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+fn named_fields(input: &DeriveInput) -> Result<Vec<&Field>> {
+    match &input.data {
+        Data::Struct(data) => match &data.fields {
+            StructFields::Named(fields) => Ok(fields.named.iter().collect()),
+            _ => Err(Error::new_spanned(input, "expected a struct with named fields")),
+        },
+        _ => Err(Error::new_spanned(input, "expected a struct with named fields")),
+    }
+}
+
+/// the options of every `#[serde(...)]` attribute in `attrs`
+fn serde_options(attrs: &[Attribute]) -> Result<Vec<NestedMeta>> {
+    let mut options = vec![];
+    for attr in attrs.iter().filter(|attr| attr.path.is_ident("serde")) {
+        if let Meta::List(list) = attr.parse_meta()? {
+            options.extend(list.nested);
+        }
+    }
+    Ok(options)
+}
+
+/// the `serialize` half of an option such as `rename(serialize = "a", deserialize = "b")`
+fn serialize_value(list: &MetaList) -> Option<LitStr> {
+    list.nested.iter().find_map(|nested| match nested {
+        NestedMeta::Meta(Meta::NameValue(pair)) if pair.path.is_ident("serialize") => {
+            match &pair.lit {
+                Lit::Str(value) => Some(value.clone()),
+                _ => None,
+            }
+        }
+        _ => None,
+    })
+}
+
+/// the name or rule an option such as `rename` sets for serialization, if it sets one
+fn serialized_option(nested: &NestedMeta, option: &str) -> Option<LitStr> {
+    match nested {
+        NestedMeta::Meta(Meta::NameValue(pair)) if pair.path.is_ident(option) => {
+            match &pair.lit {
+                Lit::Str(value) => Some(value.clone()),
+                _ => None,
+            }
+        }
+        NestedMeta::Meta(Meta::List(list)) if list.path.is_ident(option) => serialize_value(list),
+        _ => None,
+    }
+}
+
+/// `field` renamed by a container's `#[serde(rename_all = "...")]` rule
+fn rename_field(rule: &LitStr, field: &str) -> Result<String> {
+    let pascal = || {
+        field
+            .split('_')
+            .map(|word| {
+                let mut chars = word.chars();
+                chars.next().map_or_else(String::new, |first| {
+                    first.to_uppercase().chain(chars).collect::<String>()
+                })
+            })
+            .collect::<String>()
+    };
+    let renamed = match rule.value().as_str() {
+        "lowercase" | "snake_case" => field.to_string(),
+        "UPPERCASE" | "SCREAMING_SNAKE_CASE" => field.to_ascii_uppercase(),
+        "PascalCase" => pascal(),
+        "camelCase" => {
+            let pascal = pascal();
+            let mut chars = pascal.chars();
+            chars.next().map_or_else(String::new, |first| {
+                first.to_lowercase().chain(chars).collect::<String>()
+            })
+        }
+        "kebab-case" => field.replace('_', "-"),
+        "SCREAMING-KEBAB-CASE" => field.to_ascii_uppercase().replace('_', "-"),
+        _ => return Err(Error::new_spanned(rule, "unknown rename_all rule")),
+    };
+    Ok(renamed)
+}
+
+/// the name serde stores `field` under, `None` when it isn't stored as a field of its own
+fn serialized_name(input: &DeriveInput, field: &Field) -> Result<Option<String>> {
+    let Some(ident) = &field.ident else {
+        return Ok(None);
+    };
+    let mut name = ident.unraw().to_string();
+    for option in serde_options(&input.attrs)? {
+        if let Some(rule) = serialized_option(&option, "rename_all") {
+            name = rename_field(&rule, &name)?;
+        }
+    }
+    for option in serde_options(&field.attrs)? {
+        if let Some(rename) = serialized_option(&option, "rename") {
+            name = rename.value();
+        }
+        match option {
+            NestedMeta::Meta(Meta::Path(path))
+                if path.is_ident("skip")
+                    || path.is_ident("skip_serializing")
+                    || path.is_ident("flatten") =>
+            {
+                return Ok(None);
+            }
+            _ => {}
+        }
+    }
+    Ok(Some(name))
+}
+
+fn expand_fields(input: &DeriveInput) -> Result<TokenStream2> {
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let mut constants = vec![];
+    for field in named_fields(input)? {
+        let Some(path) = serialized_name(input, field)? else {
+            continue;
+        };
+        let Some(ident) = &field.ident else {
+            continue;
+        };
+        let constant = format_ident!("{}", ident.unraw().to_string().to_uppercase());
+        let ty = &field.ty;
+        constants.push(quote! {
+            pub const #constant: ::aws_rust::query::Field<Self, #ty> =
+                ::aws_rust::query::Field::new(#path);
+        });
+    }
+    Ok(quote! {
+        impl #impl_generics #name #ty_generics #where_clause {
+            #(#constants)*
+        }
+    })
+}
+
+struct ModelOptions {
+    collection: String,
+    soft_delete: bool,
+    audit: bool,
+}
+
+fn model_options(input: &DeriveInput) -> Result<ModelOptions> {
+    let mut collection = None;
+    let mut soft_delete = false;
+    let mut audit = true;
+    for attr in input.attrs.iter().filter(|attr| attr.path.is_ident("model")) {
+        let Meta::List(options) = attr.parse_meta()? else {
+            return Err(Error::new_spanned(attr, "expected #[model(collection = \"...\")]"));
+        };
+        for nested in options.nested {
+            match nested {
+                NestedMeta::Meta(Meta::NameValue(pair)) if pair.path.is_ident("collection") => {
+                    match pair.lit {
+                        Lit::Str(name) => collection = Some(name.value()),
+                        lit => return Err(Error::new_spanned(lit, "expected a string")),
+                    }
+                }
+                NestedMeta::Meta(Meta::NameValue(pair)) if pair.path.is_ident("audit") => {
+                    match pair.lit {
+                        Lit::Bool(flag) => audit = flag.value,
+                        lit => return Err(Error::new_spanned(lit, "expected a bool")),
+                    }
+                }
+                NestedMeta::Meta(Meta::Path(path)) if path.is_ident("soft_delete") => {
+                    soft_delete = true;
+                }
+                nested => return Err(Error::new_spanned(nested, "unknown model option")),
+            }
+        }
+    }
+    let collection = collection.ok_or_else(|| {
+        Error::new_spanned(&input.ident, "missing #[model(collection = \"...\")]")
+    })?;
+    Ok(ModelOptions {
+        collection,
+        soft_delete,
+        audit,
+    })
+}
+
+/// which declared index a field's key belongs to
+#[derive(PartialEq, Eq)]
+enum Group {
+    Single,
+    Compound(String),
+    Text,
+}
+
+struct Index {
+    group: Group,
+    keys: Vec<(String, TokenStream2)>,
+    unique: bool,
+    sparse: bool,
+    ttl: Option<u64>,
+}
+
+/// seconds in a duration like `30d`, `12h`, `15m` or `45s`
+fn parse_ttl(lit: &Lit) -> Result<u64> {
+    let Lit::Str(value) = lit else {
+        return Err(Error::new_spanned(lit, "expected a duration such as \"30d\""));
+    };
+    let value = value.value();
+    let unit_at = value.char_indices().last().map_or(0, |(at, _)| at);
+    let (amount, unit) = value.split_at(unit_at);
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => 0,
+    };
+    match amount.parse::<u64>() {
+        Ok(amount) if multiplier > 0 => Ok(amount * multiplier),
+        _ => Err(Error::new_spanned(lit, "expected a duration such as \"30d\"")),
+    }
+}
+
+fn parse_index(attr: &Attribute, path: &str) -> Result<Index> {
+    let mut group = Group::Single;
+    let mut order = 1;
+    let mut unique = false;
+    let mut sparse = false;
+    let mut ttl = None;
+    let nested = match attr.parse_meta()? {
+        Meta::Path(_) => vec![],
+        Meta::List(list) => list.nested.into_iter().collect(),
+        Meta::NameValue(_) => return Err(Error::new_spanned(attr, "expected #[index(...)]")),
+    };
+    for nested in nested {
+        match nested {
+            NestedMeta::Meta(Meta::Path(option)) if option.is_ident("unique") => unique = true,
+            NestedMeta::Meta(Meta::Path(option)) if option.is_ident("sparse") => sparse = true,
+            NestedMeta::Meta(Meta::Path(option)) if option.is_ident("text") => {
+                group = Group::Text;
+            }
+            NestedMeta::Meta(Meta::NameValue(pair)) if pair.path.is_ident("compound") => {
+                match &pair.lit {
+                    Lit::Str(name) => group = Group::Compound(name.value()),
+                    lit => return Err(Error::new_spanned(lit, "expected a string")),
+                }
+            }
+            NestedMeta::Meta(Meta::NameValue(pair)) if pair.path.is_ident("order") => {
+                order = match &pair.lit {
+                    Lit::Int(int) => int.base10_parse::<i32>()?,
+                    lit => return Err(Error::new_spanned(lit, "expected 1 or -1")),
+                };
+                if order != 1 && order != -1 {
+                    return Err(Error::new_spanned(&pair.lit, "expected 1 or -1"));
+                }
+            }
+            NestedMeta::Meta(Meta::NameValue(pair)) if pair.path.is_ident("ttl") => {
+                ttl = Some(parse_ttl(&pair.lit)?);
+            }
+            nested => return Err(Error::new_spanned(nested, "unknown index option")),
+        }
+    }
+    if ttl.is_some() && group != Group::Single {
+        return Err(Error::new(attr.span(), "a ttl index can only have one field"));
+    }
+    let key = if group == Group::Text {
+        quote!("text")
+    } else {
+        quote!(#order)
+    };
+    Ok(Index {
+        group,
+        keys: vec![(path.to_string(), key)],
+        unique,
+        sparse,
+        ttl,
+    })
+}
+
+/// the declared indexes, merging compound and text keys into the index of their group
+fn declared_indexes(input: &DeriveInput) -> Result<Vec<Index>> {
+    let mut indexes: Vec<Index> = vec![];
+    for field in named_fields(input)? {
+        let attrs = field.attrs.iter().filter(|attr| attr.path.is_ident("index"));
+        for attr in attrs {
+            let Some(path) = serialized_name(input, field)? else {
+                return Err(Error::new_spanned(attr, "only serialized fields can be indexed"));
+            };
+            let index = parse_index(attr, &path)?;
+            let existing = indexes
+                .iter_mut()
+                .find(|existing| index.group != Group::Single && existing.group == index.group);
+            match existing {
+                Some(existing) => {
+                    existing.keys.extend(index.keys);
+                    existing.unique |= index.unique;
+                    existing.sparse |= index.sparse;
+                }
+                None => indexes.push(index),
+            }
+        }
+    }
+    Ok(indexes)
+}
+
+fn expand_index(index: &Index, soft_delete: bool) -> TokenStream2 {
+    let keys = index.keys.iter().map(|(path, key)| quote!(#path: #key));
+    let mut options = vec![];
+    if index.unique {
+        options.push(quote!(.unique(true)));
+    }
+    if index.sparse {
+        options.push(quote!(.sparse(true)));
+    }
+    // a deleted document shouldn't keep a value taken until it is purged
+    if index.unique && soft_delete {
+        options.push(quote! {
+            .partial_filter_expression(::bson::doc! {
+                ::aws_rust::database::DELETED_AT: ::bson::Bson::Null
+            })
+        });
+    }
+    if let Some(seconds) = index.ttl {
+        options.push(quote!(.expire_after(::std::time::Duration::from_secs(#seconds))));
+    }
+    let options = if options.is_empty() {
+        quote!(::std::option::Option::None)
+    } else {
+        quote!(::mongodb::options::IndexOptions::builder() #(#options)* .build())
+    };
+    quote! {
+        ::mongodb::IndexModel::builder()
+            .keys(::bson::doc! { #(#keys),* })
+            .options(#options)
+            .build()
+    }
+}
+
+/// the single type argument of `ty` when its last path segment is `name`, e.g. `Vec<T>`
+fn type_argument<'a>(ty: &'a Type, name: &str) -> Option<&'a Type> {
+    let Type::Path(path) = ty else {
+        return None;
+    };
+    let segment = path.path.segments.last()?;
+    let PathArguments::AngleBracketed(arguments) = &segment.arguments else {
+        return None;
+    };
+    match arguments.args.first() {
+        Some(GenericArgument::Type(argument)) if segment.ident == name => Some(argument),
+        _ => None,
+    }
+}
+
+/// the model a `Ref<T>`, `Vec<Ref<T>>` or `Option<Ref<T>>` field refers to, and whether it
+/// refers to a single one
+fn referenced_model(ty: &Type) -> Option<(&Type, bool)> {
+    if let Some(model) = type_argument(ty, "Ref") {
+        return Some((model, true));
+    }
+    if let Some(model) = type_argument(ty, "Option").and_then(|inner| type_argument(inner, "Ref")) {
+        return Some((model, true));
+    }
+    let model = type_argument(ty, "Vec").and_then(|inner| type_argument(inner, "Ref"))?;
+    Some((model, false))
+}
+
+fn expand_relations(input: &DeriveInput) -> Result<Vec<TokenStream2>> {
+    let mut relations = vec![];
+    for field in named_fields(input)? {
+        let Some((model, single)) = referenced_model(&field.ty) else {
+            continue;
+        };
+        let Some(path) = serialized_name(input, field)? else {
+            continue;
+        };
+        relations.push(if single {
+            quote!(::aws_rust::database::Relation::one::<#model>(#path))
+        } else {
+            quote!(::aws_rust::database::Relation::many::<#model>(#path))
+        });
+    }
+    Ok(relations)
+}
+
+/// reads `_id` straight from its field rather than serializing the whole document
+fn expand_document_id(input: &DeriveInput) -> Result<Option<TokenStream2>> {
+    for field in named_fields(input)? {
+        if serialized_name(input, field)?.as_deref() == Some("_id") {
+            let ident = &field.ident;
+            return Ok(Some(quote! {
+                fn document_id(&self) -> ::bson::Bson {
+                    ::bson::Bson::from(::std::clone::Clone::clone(&self.#ident))
+                }
+            }));
+        }
+    }
+    Ok(None)
+}
+
+fn expand_model(input: &DeriveInput) -> Result<TokenStream2> {
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let ModelOptions {
+        collection,
+        soft_delete,
+        audit,
+    } = model_options(input)?;
+    let indexes = declared_indexes(input)?
+        .iter()
+        .map(|index| expand_index(index, soft_delete))
+        .collect::<Vec<_>>();
+    let relations = expand_relations(input)?;
+    let document_id = expand_document_id(input)?;
+    Ok(quote! {
+        impl #impl_generics ::aws_rust::database::Model for #name #ty_generics #where_clause {
+            const SOFT_DELETE: bool = #soft_delete;
+            const AUDIT: bool = #audit;
+
+            fn collection_name<'a>() -> &'a str {
+                #collection
+            }
+
+            #document_id
+
+            fn relations() -> ::std::vec::Vec<::aws_rust::database::Relation> {
+                ::std::vec![#(#relations),*]
+            }
+
+            fn indexes() -> ::std::vec::Vec<::mongodb::IndexModel> {
+                ::std::vec![#(#indexes),*]
+            }
+        }
+    })
+}
@@ -0,0 +1,179 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use bson::{doc, Document};
+use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
+use serde::{Deserialize, Serialize};
+
+use crate::models::{
+    api_key::{ApiKey, ApiKeyUsage, Normalized, Tier},
+    rate_limit::{RateLimitBucket, RateLimitWindow},
+    todo::Todo,
+    user::User,
+};
+use aws_rust::{
+    audit::{AuditEntry, MAX_AUDITED_WRITE},
+    auth::Admin,
+    config::Env,
+    database::{
+        with_transaction, IndexPlan, Model, PageRequest, Paginated, Populate, PAGINATION_MAX,
+    },
+    errors::ApiError,
+    validation::{trim, Valid, Validate, Validator},
+};
+
+#[derive(Deserialize, Serialize)]
+pub struct IssueKey {
+    pub label: String,
+    pub tier: Tier,
+}
+
+impl Validate for IssueKey {
+    fn normalize(&mut self) {
+        trim(&mut self.label);
+    }
+
+    fn validate(&self, validator: &mut Validator) {
+        validator.length("label", &self.label, 1, 64);
+    }
+}
+
+#[derive(Serialize)]
+pub struct IssuedKey {
+    /// the plaintext key, it cannot be recovered after this response
+    pub key: String,
+    #[serde(flatten)]
+    pub api_key: Normalized,
+}
+
+pub async fn issue_key(_: Admin, body: Valid<IssueKey>) -> Result<HttpResponse, ApiError> {
+    let (api_key, key) = ApiKey::issue(&body.label, body.tier).await?;
+    Ok(HttpResponse::Created().json(IssuedKey {
+        key,
+        api_key: api_key.normalize(),
+    }))
+}
+
+pub async fn revoke_key(_: Admin, id: web::Path<String>) -> Result<HttpResponse, ApiError> {
+    let revoked = ApiKey::find_one_and_update(
+        doc! { "_id": id.as_str() },
+        doc! { "$set": { "revoked_at": bson::DateTime::now() } },
+    )
+    .await?;
+    revoked.map_or_else(
+        || Err(ApiError::NotFound("no api key found".to_string())),
+        |api_key| Ok(HttpResponse::Ok().json(api_key.normalize())),
+    )
+}
+
+#[derive(Serialize)]
+pub struct Purged {
+    pub todos: usize,
+}
+
+/// removes todos soft deleted longer than the retention period along with the users'
+/// references to them, meant to run on a schedule
+pub async fn purge_deleted(_: Admin, env: web::Data<Env>) -> Result<HttpResponse, ApiError> {
+    let retention = chrono::Duration::days(env.soft_delete_retention_days);
+    let ids = Todo::expired_deletions(retention).await?;
+    // each batch of todos goes with the references to it, so a failure between the two writes
+    // can't leave users pointing at purged todos
+    for batch in ids.chunks(MAX_AUDITED_WRITE) {
+        with_transaction(|session| {
+            let batch = batch.to_vec();
+            Box::pin(async move {
+                Todo::delete_many_with_session(doc! { "_id": { "$in": &batch } }, session).await?;
+                User::update_many_with_session(
+                    doc! { "todos": { "$in": &batch } },
+                    doc! { "$pull": { "todos": { "$in": &batch } } },
+                    session,
+                )
+                .await?;
+                Ok(())
+            })
+        })
+        .await?;
+    }
+    Ok(HttpResponse::Ok().json(Purged { todos: ids.len() }))
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct SyncIndexesQuery {
+    /// report the plan without changing anything
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// the outcome of syncing one collection, its plan or why it failed
+#[derive(Serialize)]
+pub struct IndexSync {
+    pub collection: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub plan: Option<IndexPlan>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+async fn sync_model_indexes<M: Model>(dry_run: bool) -> IndexSync {
+    let collection = M::collection_name();
+    match M::sync_indexes(dry_run).await {
+        Ok(plan) => IndexSync {
+            collection,
+            plan: Some(plan),
+            error: None,
+        },
+        Err(err) => {
+            tracing::error!("syncing the indexes of {collection} failed: {err:#}");
+            IndexSync {
+                collection,
+                plan: None,
+                error: Some(format!("{err:#}")),
+            }
+        }
+    }
+}
+
+/// reconciles every collection's indexes with the ones its model declares, one collection
+/// at a time so a failure leaves the others to finish. 207 when any of them failed
+pub async fn sync_indexes(
+    _: Admin,
+    query: web::Query<SyncIndexesQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let dry_run = query.dry_run;
+    let results = vec![
+        sync_model_indexes::<Todo>(dry_run).await,
+        sync_model_indexes::<User>(dry_run).await,
+        sync_model_indexes::<ApiKey>(dry_run).await,
+        sync_model_indexes::<ApiKeyUsage>(dry_run).await,
+        sync_model_indexes::<RateLimitWindow>(dry_run).await,
+        sync_model_indexes::<RateLimitBucket>(dry_run).await,
+        sync_model_indexes::<AuditEntry>(dry_run).await,
+    ];
+    if results.iter().any(|result| result.error.is_some()) {
+        return Ok(HttpResponse::MultiStatus().json(results));
+    }
+    Ok(HttpResponse::Ok().json(results))
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ListUsersQuery {
+    pub page: Option<u64>,
+    pub limit: Option<u64>,
+}
+
+/// users newest first, each with their todos
+pub async fn list_users(
+    req: HttpRequest,
+    _: Admin,
+    query: web::Query<ListUsersQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let request = PageRequest::new(query.page, query.limit, PAGINATION_MAX);
+    let opts = request.options(doc! { "created_at": -1, "_id": -1 })?;
+    let populate = [Populate::of::<User>("todos")?];
+    let paginated = Paginated::fetch(
+        request,
+        User::list_populate::<User, _>(None::<Document>, &populate, Some(opts)),
+        User::count(),
+    )
+    .await?;
+    let paginated = paginated.map(|users| users.par_iter().map(User::normalize).collect());
+    Ok(paginated.respond(&req))
+}
@@ -0,0 +1,11 @@
+use actix_web::web::{self, ServiceConfig};
+
+pub mod controller;
+
+pub fn router(cfg: &mut ServiceConfig) {
+    cfg.route("/keys", web::post().to(controller::issue_key));
+    cfg.route("/keys/{id}", web::delete().to(controller::revoke_key));
+    cfg.route("/purge", web::post().to(controller::purge_deleted));
+    cfg.route("/indexes/sync", web::post().to(controller::sync_indexes));
+    cfg.route("/users", web::get().to(controller::list_users));
+}
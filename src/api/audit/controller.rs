@@ -0,0 +1,50 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use bson::{doc, Document};
+use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
+use serde::{Deserialize, Serialize};
+
+use aws_rust::{
+    audit::AuditEntry,
+    auth::Admin,
+    database::{Model, PageRequest, Paginated, PAGINATION_MAX},
+    errors::ApiError,
+};
+
+#[derive(Deserialize, Serialize)]
+pub struct HistoryQuery {
+    pub collection: String,
+    /// a single document's history, otherwise the whole collection's
+    pub id: Option<String>,
+    pub page: Option<u64>,
+    pub limit: Option<u64>,
+}
+
+impl HistoryQuery {
+    fn filter(&self) -> Document {
+        let mut filter = doc! { "collection": &self.collection };
+        if let Some(id) = &self.id {
+            filter.insert("document_id", id);
+        }
+        filter
+    }
+}
+
+/// audit entries newest first
+pub async fn history(
+    req: HttpRequest,
+    _: Admin,
+    query: web::Query<HistoryQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let filter = query.filter();
+    let request = PageRequest::new(query.page, query.limit, PAGINATION_MAX);
+    let opts = request.options(doc! { "timestamp": -1, "_id": -1 })?;
+    let paginated = Paginated::fetch(
+        request,
+        AuditEntry::list(Some(filter.clone()), Some(opts)),
+        AuditEntry::count_documents(Some(filter)),
+    )
+    .await?;
+    let paginated =
+        paginated.map(|entries| entries.par_iter().map(AuditEntry::normalize).collect());
+    Ok(paginated.respond(&req))
+}
@@ -0,0 +1,7 @@
+use actix_web::web::{self, ServiceConfig};
+
+pub mod controller;
+
+pub fn router(cfg: &mut ServiceConfig) {
+    cfg.route("", web::get().to(controller::history));
+}
@@ -0,0 +1,73 @@
+use std::{collections::BTreeMap, future::Future, time::Duration};
+
+use actix_web::{web, HttpResponse};
+use aws_rust::{config::Env, database};
+use serde::Serialize;
+use tokio::time::{timeout, Instant};
+
+use crate::prisma_models;
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Serialize)]
+pub struct Dependency {
+    pub status: &'static str,
+    pub latency_ms: u128,
+}
+
+#[derive(Serialize)]
+pub struct Health {
+    pub status: &'static str,
+    pub version: &'static str,
+    pub stage: String,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub dependencies: BTreeMap<&'static str, Dependency>,
+}
+
+async fn probe(name: &str, check: impl Future<Output = anyhow::Result<()>>) -> Dependency {
+    let started = Instant::now();
+    let status = match timeout(PROBE_TIMEOUT, check).await {
+        Ok(Ok(())) => "up",
+        Ok(Err(err)) => {
+            tracing::error!("readiness probe for {name} failed: {err:?}");
+            "down"
+        }
+        Err(_) => {
+            tracing::error!("readiness probe for {name} timed out");
+            "down"
+        }
+    };
+    Dependency {
+        status,
+        latency_ms: started.elapsed().as_millis(),
+    }
+}
+
+pub async fn live(env: web::Data<Env>) -> HttpResponse {
+    HttpResponse::Ok().json(Health {
+        status: "ok",
+        version: env!("CARGO_PKG_VERSION"),
+        stage: env.stage.clone(),
+        dependencies: BTreeMap::new(),
+    })
+}
+
+pub async fn ready(env: web::Data<Env>) -> HttpResponse {
+    let (mongodb, mysql) = futures::join!(
+        probe("mongodb", database::ping()),
+        probe("mysql", prisma_models::ping())
+    );
+    let dependencies = BTreeMap::from([("mongodb", mongodb), ("mysql", mysql)]);
+    let ready = dependencies.values().all(|dependency| dependency.status == "up");
+    let health = Health {
+        status: if ready { "ok" } else { "unavailable" },
+        version: env!("CARGO_PKG_VERSION"),
+        stage: env.stage.clone(),
+        dependencies,
+    };
+    if ready {
+        HttpResponse::Ok().json(health)
+    } else {
+        HttpResponse::ServiceUnavailable().json(health)
+    }
+}
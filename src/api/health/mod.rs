@@ -0,0 +1,8 @@
+use actix_web::web::{self, ServiceConfig};
+
+pub mod controller;
+
+pub fn router(cfg: &mut ServiceConfig) {
+    cfg.route("/live", web::get().to(controller::live));
+    cfg.route("/ready", web::get().to(controller::ready));
+}
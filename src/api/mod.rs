@@ -1,13 +1,22 @@
 use actix_web::web::{scope, ServiceConfig};
+use aws_rust::config::Features;
 
+pub mod admin;
+pub mod audit;
 pub mod dev;
+pub mod health;
 pub mod planetscale;
 pub mod todos;
 pub mod users;
 
-pub fn routes(cfg: &mut ServiceConfig) {
-    cfg.service(scope("/developer").configure(dev::router));
+pub fn routes(cfg: &mut ServiceConfig, features: &Features) {
+    if features.developer_routes {
+        cfg.service(scope("/developer").configure(dev::router));
+    }
+    cfg.service(scope("/admin").configure(admin::router));
+    cfg.service(scope("/audit").configure(audit::router));
+    cfg.service(scope("/health").configure(health::router));
     cfg.service(scope("/todos").configure(todos::router));
     cfg.service(scope("/users").configure(users::router));
-    cfg.service(scope("/planetscale").configure(planetscale::router));
+    cfg.service(scope("/planetscale").configure(|cfg| planetscale::router(cfg, features)));
 }
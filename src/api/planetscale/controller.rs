@@ -1,42 +1,148 @@
+use std::time::Duration;
+
 use actix_web::{web, HttpResponse};
-use serde_json::json;
 
 use crate::{
-    prisma::user,
-    prisma_models::{user_model::CreateUser, PaginationQuery, PrismaHelpers},
+    prisma::{password_reset, user},
+    prisma_models::{
+        password_reset_model::{
+            ChangePassword, ConfirmPasswordReset, RequestPasswordReset, RESET_TOKEN_TTL_MINUTES,
+        },
+        user_model::{verify_password, CreateUser, Login, Profile},
+        PaginationQuery, PrismaHelpers,
+    },
+};
+use aws_rust::{
+    auth::{issue_token, Admin, AuthenticatedUser},
+    config::Env,
+    errors::ApiError,
+    notify::{Notification, Notifier},
+    validation::Valid,
 };
 
-pub async fn list_users(query: web::Query<PaginationQuery>) -> HttpResponse {
-    let users = user::Data::paginate(query.into_inner()).await;
-    match users {
-        Ok(users) => HttpResponse::Ok().json(users),
-        Err(err) => HttpResponse::InternalServerError().json(json!({ "error": err.to_string() })),
+/// lets an account act on itself, and the admin key act on any account
+fn authorize_account(
+    caller: Option<AuthenticatedUser>,
+    admin: Option<Admin>,
+    id: &str,
+) -> Result<(), ApiError> {
+    match (caller, admin) {
+        (_, Some(_)) => Ok(()),
+        (Some(caller), None) if caller.id == id => Ok(()),
+        (Some(_), None) => Err(ApiError::Forbidden(
+            "users can only access their own account".to_string(),
+        )),
+        (None, None) => Err(ApiError::Unauthorized(
+            "missing bearer token or admin key".to_string(),
+        )),
     }
 }
 
-pub async fn read_by_id(id: web::Path<String>) -> HttpResponse {
-    let user = user::Data::read_by_id(&id).await;
-    match user {
-        Ok(user) => user.map_or_else(
-            || HttpResponse::NotFound().json(json!({ "error": "no user found" })),
-            |found| HttpResponse::Ok().json(found),
-        ),
-        Err(err) => HttpResponse::InternalServerError().json(json!({ "error": err.to_string() })),
+pub async fn list_users(
+    _: Admin,
+    query: web::Query<PaginationQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let users = user::Data::paginate(query.into_inner()).await?;
+    let users = users.into_iter().map(Profile::from).collect::<Vec<_>>();
+    Ok(HttpResponse::Ok().json(users))
+}
+
+pub async fn read_by_id(
+    caller: Option<AuthenticatedUser>,
+    admin: Option<Admin>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    authorize_account(caller, admin, &id)?;
+    let user = user::Data::read_by_id(&id).await?;
+    user.map_or_else(
+        || Err(ApiError::NotFound("no user found".to_string())),
+        |found| Ok(HttpResponse::Ok().json(Profile::from(found))),
+    )
+}
+
+pub async fn create_user(body: Valid<CreateUser>) -> Result<HttpResponse, ApiError> {
+    let user = user::Data::create(body.into_inner()).await?;
+    Ok(HttpResponse::Created().json(Profile::from(user)))
+}
+
+pub async fn delete_by_id(
+    caller: Option<AuthenticatedUser>,
+    admin: Option<Admin>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    authorize_account(caller, admin, &id)?;
+    let user = user::Data::delete(&id).await?;
+    Ok(HttpResponse::Ok().json(Profile::from(user)))
+}
+
+pub async fn login(env: web::Data<Env>, body: Valid<Login>) -> Result<HttpResponse, ApiError> {
+    let user = user::Data::authenticate(&body.email, &body.password).await?;
+    // the same response for an unknown email and a wrong password
+    let user = user.ok_or_else(|| ApiError::Unauthorized("invalid email or password".to_string()))?;
+    let token = issue_token(&env, &user.id, &user.email, user.token_version)?;
+    Ok(HttpResponse::Ok().json(token))
+}
+
+pub async fn change_password(
+    caller: AuthenticatedUser,
+    id: web::Path<String>,
+    body: Valid<ChangePassword>,
+) -> Result<HttpResponse, ApiError> {
+    if caller.id != *id {
+        return Err(ApiError::Forbidden(
+            "cannot change another user's password".to_string(),
+        ));
     }
+    let user = user::Data::read_by_id(&id)
+        .await?
+        .ok_or_else(|| ApiError::NotFound("no user found".to_string()))?;
+    if !verify_password(&user.password, &body.current_password)? {
+        return Err(ApiError::Forbidden(
+            "current password is incorrect".to_string(),
+        ));
+    }
+    user::Data::set_password(&user.id, &body.new_password).await?;
+    Ok(HttpResponse::NoContent().finish())
 }
 
-pub async fn create_user(body: web::Json<CreateUser>) -> HttpResponse {
-    let created = user::Data::create(body.into_inner()).await;
-    match created {
-        Ok(user) => HttpResponse::Created().json(user),
-        Err(err) => HttpResponse::InternalServerError().json(json!({ "error": err.to_string() })),
+/// the least time a reset request takes, covering the store and send a registered email
+/// needs so an unregistered one can't be told apart by how fast it returns
+const RESET_RESPONSE_FLOOR: Duration = Duration::from_millis(750);
+
+async fn send_reset(notifier: &dyn Notifier, email: &str) -> anyhow::Result<()> {
+    if let Some((user, token)) = password_reset::Data::issue(email).await? {
+        let notification = Notification {
+            to: user.email,
+            subject: "reset your password".to_string(),
+            body: format!(
+                "use this token to reset your password: {token}\n\
+                 it expires in {RESET_TOKEN_TTL_MINUTES} minutes"
+            ),
+        };
+        notifier.send(&notification).await?;
     }
+    Ok(())
 }
 
-pub async fn delete_by_id(id: web::Path<String>) -> HttpResponse {
-    let user = user::Data::delete(&id).await;
-    match user {
-        Ok(user) => HttpResponse::Ok().json(user),
-        Err(err) => HttpResponse::InternalServerError().json(json!({ "error": err.to_string() })),
+pub async fn request_password_reset(
+    notifier: web::Data<dyn Notifier>,
+    body: Valid<RequestPasswordReset>,
+) -> Result<HttpResponse, ApiError> {
+    let floor = tokio::time::Instant::now() + RESET_RESPONSE_FLOOR;
+    // accepted either way so the endpoint can't be used to discover registered emails
+    if let Err(err) = send_reset(notifier.as_ref(), &body.email).await {
+        tracing::error!("sending a password reset failed: {err:?}");
     }
+    tokio::time::sleep_until(floor).await;
+    Ok(HttpResponse::Accepted().finish())
+}
+
+pub async fn confirm_password_reset(
+    body: Valid<ConfirmPasswordReset>,
+) -> Result<HttpResponse, ApiError> {
+    let user = user::Data::reset_password(&body.token, &body.password).await?;
+    user.map_or_else(
+        || Err(ApiError::validation("token", "is invalid or has expired")),
+        |_| Ok(HttpResponse::NoContent().finish()),
+    )
 }
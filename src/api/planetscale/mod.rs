@@ -1,10 +1,27 @@
 use actix_web::web::{self, ServiceConfig};
+use aws_rust::config::Features;
 
 pub mod controller;
 
-pub fn router(cfg: &mut ServiceConfig) {
+pub fn router(cfg: &mut ServiceConfig, features: &Features) {
     cfg.route("/users", web::get().to(controller::list_users));
     cfg.route("/users", web::post().to(controller::create_user));
     cfg.route("/users/{id}", web::get().to(controller::read_by_id));
     cfg.route("/users/{id}", web::delete().to(controller::delete_by_id));
+    cfg.route(
+        "/users/{id}/password",
+        web::put().to(controller::change_password),
+    );
+    cfg.route("/auth/login", web::post().to(controller::login));
+    if !features.password_reset {
+        return;
+    }
+    cfg.route(
+        "/auth/password-reset",
+        web::post().to(controller::request_password_reset),
+    );
+    cfg.route(
+        "/auth/password-reset/confirm",
+        web::post().to(controller::confirm_password_reset),
+    );
 }
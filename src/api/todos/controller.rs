@@ -1,11 +1,23 @@
-use actix_web::{web, HttpResponse};
-use bson::doc;
+use actix_web::{web, HttpRequest, HttpResponse};
+use bson::{doc, Document};
+use chrono::{DateTime, Utc};
 use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
 use serde::{Deserialize, Serialize};
-use serde_json::json;
 
-use crate::models::{todo::Todo, user::User};
-use aws_rust::database::{generate_nanoid, ListQueryOptions, Model};
+use crate::models::{
+    todo::{SearchHit, Todo},
+    user::User,
+};
+use aws_rust::{
+    auth::AuthenticatedUser,
+    database::{
+        generate_nanoid, with_transaction, FindQueryOptions, Model, Page, PageQueryOptions,
+        PageRequest, Paginated, PAGINATION_MAX,
+    },
+    errors::ApiError,
+    query::Filter,
+    validation::{trim, Valid, Validate, Validator},
+};
 
 #[derive(Deserialize, Serialize)]
 pub struct CreateTodo {
@@ -13,72 +25,349 @@ pub struct CreateTodo {
     pub user: String,
 }
 
+impl Validate for CreateTodo {
+    fn normalize(&mut self) {
+        trim(&mut self.task);
+        trim(&mut self.user);
+    }
+
+    fn validate(&self, validator: &mut Validator) {
+        validator
+            .length("task", &self.task, 1, 280)
+            .length("user", &self.user, 1, 64);
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct UpdateTodo {
+    pub task: Option<String>,
+    pub complete: Option<bool>,
+}
+
+impl Validate for UpdateTodo {
+    fn normalize(&mut self) {
+        if let Some(task) = self.task.as_mut() {
+            trim(task);
+        }
+    }
+
+    fn validate(&self, validator: &mut Validator) {
+        if let Some(task) = &self.task {
+            validator.length("task", task, 1, 280);
+        }
+        if self.task.is_none() && self.complete.is_none() {
+            validator.error("body", "expected task or complete");
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct FilterById {
     pub id: String,
 }
 
-pub async fn create_todo(body: web::Json<CreateTodo>) -> HttpResponse {
+#[derive(Deserialize, Serialize)]
+pub struct SearchTodosQuery {
+    /// mongo text search syntax, `"exact phrase"` and `-excluded` terms included
+    pub q: String,
+    pub limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct SearchResults {
+    pub items: Vec<SearchHit>,
+}
+
+/// fields callers may sort on, anything else is rejected rather than handed to mongo
+const SORTABLE_FIELDS: [&str; 4] = ["task", "complete", "created_at", "updated_at"];
+
+/// the most todos a list page holds, by page number or by cursor
+const LIST_LIMIT_MAX: u64 = PAGINATION_MAX;
+
+/// the most hits a search returns; mongo scores every text match before the limit applies,
+/// so searches are held to a smaller page than lists
+const SEARCH_LIMIT_MAX: i64 = 50;
+
+#[derive(Deserialize, Serialize)]
+pub struct ListTodosQuery {
+    /// pages by cursor instead of page number when present, an empty one starts at the
+    /// first page and each response carries the `next_cursor`
+    pub cursor: Option<String>,
+    pub page: Option<u64>,
+    pub limit: Option<u64>,
+    pub complete: Option<bool>,
+    pub user: Option<String>,
+    /// case-insensitive substring of the task
+    pub task: Option<String>,
+    pub created_after: Option<String>,
+    pub created_before: Option<String>,
+    pub updated_since: Option<String>,
+    /// comma separated fields, prefixed with `-` for descending, e.g. `-created_at,task`
+    pub sort: Option<String>,
+}
+
+fn parse_timestamp(field: &str, value: &str) -> Result<DateTime<Utc>, ApiError> {
+    DateTime::parse_from_rfc3339(value)
+        .map(|timestamp| timestamp.with_timezone(&Utc))
+        .map_err(|_| ApiError::validation(field, "must be an RFC 3339 timestamp"))
+}
+
+/// escapes regex metacharacters so user input only ever matches literally
+fn escape_regex(value: &str) -> String {
+    value.chars().fold(String::new(), |mut escaped, c| {
+        if "\\.^$|?*+()[]{}".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+        escaped
+    })
+}
+
+impl ListTodosQuery {
+    /// the caller's todos narrowed by every filter present on the query
+    fn filter(&self, owner: &User) -> Result<Filter<Todo>, ApiError> {
+        if matches!(&self.user, Some(user) if *user != owner.id) {
+            return Err(ApiError::Forbidden(
+                "todos of other users are not visible".to_string(),
+            ));
+        }
+        let mut filter = Todo::ID.is_in(&owner.todos);
+        if let Some(complete) = self.complete {
+            filter = filter.and(Todo::COMPLETE.eq(complete));
+        }
+        if let Some(task) = self.task.as_deref().map(str::trim) {
+            if task.is_empty() || task.chars().count() > 280 {
+                return Err(ApiError::validation(
+                    "task",
+                    "must be between 1 and 280 characters",
+                ));
+            }
+            filter = filter.and(Todo::TASK.regex(&escape_regex(task), "i"));
+        }
+        if let Some(after) = &self.created_after {
+            let after = parse_timestamp("created_after", after)?;
+            filter = filter.and(Todo::CREATED_AT.gt(after));
+        }
+        if let Some(before) = &self.created_before {
+            let before = parse_timestamp("created_before", before)?;
+            filter = filter.and(Todo::CREATED_AT.lt(before));
+        }
+        if let Some(since) = &self.updated_since {
+            let since = parse_timestamp("updated_since", since)?;
+            filter = filter.and(Todo::UPDATED_AT.gte(since));
+        }
+        Ok(filter)
+    }
+
+    fn sort(&self) -> Result<Document, ApiError> {
+        let Some(sort) = self.sort.as_deref() else {
+            return Ok(doc! { "complete": 1, "created_at": -1 });
+        };
+        let mut fields = Document::new();
+        for field in sort.split(',').map(str::trim).filter(|field| !field.is_empty()) {
+            let (field, direction) = match field.strip_prefix('-') {
+                Some(field) => (field, -1),
+                None => (field, 1),
+            };
+            if !SORTABLE_FIELDS.contains(&field) {
+                return Err(ApiError::validation(
+                    "sort",
+                    format!("must be one of {}", SORTABLE_FIELDS.join(", ")),
+                ));
+            }
+            fields.insert(field, direction);
+        }
+        if fields.is_empty() {
+            return Err(ApiError::validation("sort", "is required"));
+        }
+        Ok(fields)
+    }
+}
+
+pub async fn create_todo(
+    caller: AuthenticatedUser,
+    body: Valid<CreateTodo>,
+) -> Result<HttpResponse, ApiError> {
+    let owner = User::for_caller(&caller).await?;
+    if owner.id != body.user {
+        return Err(ApiError::Forbidden(
+            "todos can only be created for yourself".to_string(),
+        ));
+    }
     let now = chrono::Utc::now();
     let todo = Todo {
         id: generate_nanoid(),
-        task: body.task.trim().to_owned(),
+        task: body.task.clone(),
         complete: false,
         created_at: now,
         updated_at: now,
+        deleted_at: None,
     };
-    match todo.save().await {
-        Ok(inserted) => {
-            // save to user
-            let update_user = User::update_one(
-                doc! { "_id": body.user.to_string() },
-                doc! { "$set": { "updated_at": now }, "$push": { "todos": inserted.id.to_string() } }
-                ,
+    // the todo and the user's reference to it commit together or not at all
+    with_transaction(|session| {
+        let todo = todo.clone();
+        let user = owner.id.clone();
+        Box::pin(async move {
+            let updated = User::update_one_with_session(
+                User::ID.eq(user),
+                User::UPDATED_AT.set(now).and(User::TODOS.push(&todo.id)),
+                session,
             )
-            .await;
-            if let Err(err) = update_user {
-                return HttpResponse::InternalServerError()
-                    .json(json!({ "error": err.to_string() }));
+            .await?;
+            if updated.matched_count == 0 {
+                return Err(ApiError::NotFound("no user found".to_string()).into());
             }
-            HttpResponse::Created().json(inserted.normalize())
-        }
-        Err(err) => HttpResponse::InternalServerError().json(json!({ "error": err.to_string() })),
+            todo.save_with_session(session).await?;
+            Ok(())
+        })
+    })
+    .await?;
+    Ok(HttpResponse::Created().json(todo.normalize()))
+}
+
+pub async fn complete_todo(
+    caller: AuthenticatedUser,
+    query: web::Query<FilterById>,
+) -> Result<HttpResponse, ApiError> {
+    let owner = User::for_caller(&caller).await?;
+    owner.authorize_todo(&query.id).await?;
+    let updated = Todo::find_one_and_update(
+        Todo::ID.eq(&query.id),
+        Todo::COMPLETE
+            .set(true)
+            .and(Todo::UPDATED_AT.set(chrono::Utc::now())),
+    )
+    .await?;
+    updated.map_or_else(
+        || Err(ApiError::NotFound("no todo found".to_string())),
+        |found| Ok(HttpResponse::Ok().json(found.normalize())),
+    )
+}
+
+pub async fn list_todos(
+    req: HttpRequest,
+    caller: AuthenticatedUser,
+    query: web::Query<ListTodosQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let owner = User::for_caller(&caller).await?;
+    let filter = query.filter(&owner)?;
+    let request = PageRequest::new(query.page, query.limit, LIST_LIMIT_MAX);
+    if let Some(cursor) = &query.cursor {
+        let opts = PageQueryOptions {
+            limit: Some(i64::try_from(request.limit).map_err(ApiError::internal)?),
+            cursor: (!cursor.is_empty()).then(|| cursor.clone()),
+            sort: Some(query.sort()?),
+            ..Default::default()
+        };
+        let page = Todo::list_page(Some(filter.into_document()), opts).await?;
+        return Ok(HttpResponse::Ok().json(Page {
+            items: page.items.par_iter().map(Todo::normalize).collect::<Vec<_>>(),
+            next_cursor: page.next_cursor,
+            has_more: page.has_more,
+        }));
     }
+    let opts = request.options(query.sort()?)?;
+    // counted with the same filter so `total` matches what paging can reach
+    let paginated = Paginated::fetch(
+        request,
+        Todo::list(Some(filter.clone()), Some(opts)),
+        Todo::count_documents(Some(filter)),
+    )
+    .await?;
+    let paginated = paginated.map(|todos| todos.par_iter().map(Todo::normalize).collect());
+    Ok(paginated.respond(&req))
 }
 
-pub async fn complete_todo(query: web::Query<FilterById>) -> HttpResponse {
-    match Todo::update_one(
-        doc! { "_id": query.id.to_string() },
-        doc! { "$set": { "complete": true, "updated_at": chrono::Utc::now() } },
+pub async fn search_todos(
+    caller: AuthenticatedUser,
+    query: web::Query<SearchTodosQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let owner = User::for_caller(&caller).await?;
+    let q = query.q.trim();
+    if q.is_empty() || q.chars().count() > 200 {
+        return Err(ApiError::validation(
+            "q",
+            "must be between 1 and 200 characters",
+        ));
+    }
+    let limit = query.limit.unwrap_or(20).clamp(1, SEARCH_LIMIT_MAX);
+    let items = Todo::search(Todo::ID.is_in(&owner.todos), q, limit).await?;
+    Ok(HttpResponse::Ok().json(SearchResults { items }))
+}
+
+pub async fn read_todo(
+    caller: AuthenticatedUser,
+    path: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let owner = User::for_caller(&caller).await?;
+    owner.authorize_todo(&path).await?;
+    let query = Todo::read(Some(Todo::ID.eq(path.as_str())), None).await?;
+    query.map_or_else(
+        || Err(ApiError::NotFound("no todo found".to_string())),
+        |found| Ok(HttpResponse::Ok().json(found.normalize())),
     )
-    .await
-    {
-        Ok(updated) => HttpResponse::Ok().json(updated),
-        Err(err) => HttpResponse::InternalServerError().json(json!({ "error": err.to_string() })),
+}
+
+pub async fn update_todo(
+    caller: AuthenticatedUser,
+    path: web::Path<String>,
+    body: Valid<UpdateTodo>,
+) -> Result<HttpResponse, ApiError> {
+    let owner = User::for_caller(&caller).await?;
+    owner.authorize_todo(&path).await?;
+    let UpdateTodo { task, complete } = body.into_inner();
+    let mut updates = Todo::UPDATED_AT.set(chrono::Utc::now());
+    if let Some(task) = task {
+        updates = updates.and(Todo::TASK.set(task));
     }
+    if let Some(complete) = complete {
+        updates = updates.and(Todo::COMPLETE.set(complete));
+    }
+    let updated = Todo::find_one_and_update(Todo::ID.eq(path.as_str()), updates).await?;
+    updated.map_or_else(
+        || Err(ApiError::NotFound("no todo found".to_string())),
+        |found| Ok(HttpResponse::Ok().json(found.normalize())),
+    )
 }
 
-pub async fn list_todos() -> HttpResponse {
-    let opts = ListQueryOptions {
-        sort: Some(doc! { "complete": 1, "created_at": -1 }),
+/// soft deletes the todo. unlike the hard delete this replaced, the owner's reference stays
+/// until the purge removes the todo, which is how `restore_todo` knows whose it is
+pub async fn delete_todo(
+    caller: AuthenticatedUser,
+    path: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let owner = User::for_caller(&caller).await?;
+    owner.authorize_todo(&path).await?;
+    let soft_deleted = Todo::soft_delete(doc! { "_id": path.as_str() }).await?;
+    if soft_deleted.matched_count == 0 {
+        return Err(ApiError::NotFound("no todo found".to_string()));
+    }
+    let opts = FindQueryOptions {
+        with_deleted: true,
         ..Default::default()
     };
-    match Todo::list(None, Some(opts)).await {
-        Ok(found) => {
-            let found = found.par_iter().map(Todo::normalize).collect::<Vec<_>>();
-            HttpResponse::Ok().json(found)
-        }
-        Err(err) => HttpResponse::InternalServerError().json(json!({ "error": err.to_string() })),
-    }
+    let deleted = Todo::read(Some(Todo::ID.eq(path.as_str())), Some(opts)).await?;
+    deleted.map_or_else(
+        || Err(ApiError::NotFound("no todo found".to_string())),
+        |found| Ok(HttpResponse::Ok().json(found.normalize())),
+    )
 }
 
-pub async fn read_todo(path: web::Path<String>) -> HttpResponse {
-    let query = Todo::read(Some(doc! { "_id": path.to_owned() }), None).await;
-    match query {
-        Ok(doc) => doc.map_or_else(
-            || HttpResponse::NotFound().json(json!({ "error": "no todo found" })),
-            |found| HttpResponse::Ok().json(found.normalize()),
-        ),
-        Err(err) => HttpResponse::InternalServerError().json(json!({ "error": err.to_string() })),
+pub async fn restore_todo(
+    caller: AuthenticatedUser,
+    path: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let owner = User::for_caller(&caller).await?;
+    if !owner.todos.iter().any(|todo| todo.refers_to(&path)) {
+        return Err(ApiError::NotFound("no todo found".to_string()));
     }
+    let restored = Todo::restore(doc! { "_id": path.as_str() }).await?;
+    if restored.matched_count == 0 {
+        return Err(ApiError::NotFound("no deleted todo found".to_string()));
+    }
+    let todo = Todo::read(Some(Todo::ID.eq(path.as_str())), None).await?;
+    todo.map_or_else(
+        || Err(ApiError::NotFound("no todo found".to_string())),
+        |found| Ok(HttpResponse::Ok().json(found.normalize())),
+    )
 }
@@ -4,7 +4,11 @@ pub mod controller;
 
 pub fn router(cfg: &mut ServiceConfig) {
     cfg.route("", web::get().to(controller::list_todos));
+    cfg.route("/search", web::get().to(controller::search_todos));
     cfg.route("/{_id}", web::get().to(controller::read_todo));
     cfg.route("", web::post().to(controller::create_todo));
     cfg.route("/complete", web::put().to(controller::complete_todo));
+    cfg.route("/{_id}", web::patch().to(controller::update_todo));
+    cfg.route("/{_id}", web::delete().to(controller::delete_todo));
+    cfg.route("/{_id}/restore", web::post().to(controller::restore_todo));
 }
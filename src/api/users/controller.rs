@@ -1,40 +1,67 @@
 use actix_web::{web, HttpResponse};
-use aws_rust::database::{generate_nanoid, Model};
-use bson::doc;
+use aws_rust::{
+    auth::AuthenticatedUser,
+    database::{generate_nanoid, Model},
+    errors::ApiError,
+    validation::{trim, Valid, Validate, Validator},
+};
 use serde::{Deserialize, Serialize};
-use serde_json::json;
 
-use crate::models::user::{Populated, User};
+use crate::models::user::User;
 
 #[derive(Deserialize, Serialize)]
+/// the profile of the calling account, whose email comes from its token
 pub struct CreateUser {
     pub username: String,
-    pub email: String,
 }
 
-pub async fn create_user(body: web::Json<CreateUser>) -> HttpResponse {
+impl Validate for CreateUser {
+    fn normalize(&mut self) {
+        trim(&mut self.username);
+    }
+
+    fn validate(&self, validator: &mut Validator) {
+        validator.length("username", &self.username, 3, 32);
+        if !self
+            .username
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            validator.error(
+                "username",
+                "may only contain letters, numbers, underscores and dashes",
+            );
+        }
+    }
+}
+
+pub async fn create_user(
+    caller: AuthenticatedUser,
+    body: Valid<CreateUser>,
+) -> Result<HttpResponse, ApiError> {
     let now = chrono::Utc::now();
+    // a second profile for the same account collides on the unique `account_id`
     let user = User {
         id: generate_nanoid(),
+        account_id: caller.id.clone(),
         username: body.username.clone(),
-        email: body.email.clone(),
+        email: caller.email.clone(),
         todos: vec![],
         created_at: now,
         updated_at: now,
     };
-    match user.save().await {
-        Ok(inserted) => HttpResponse::Created().json(inserted),
-        Err(err) => HttpResponse::InternalServerError().json(json!({ "error": err.to_string() })),
-    }
+    let inserted = user.save().await?;
+    Ok(HttpResponse::Created().json(inserted))
 }
 
-pub async fn read_user(path: web::Path<String>) -> HttpResponse {
-    let query = User::read_populate::<Populated>(doc! { "_id": path.to_owned() }, &["todos"]).await;
-    match query {
-        Ok(doc) => doc.map_or_else(
-            || HttpResponse::NotFound().json(json!({ "error": "no user found" })),
-            |found| HttpResponse::Ok().json(found.normalize()),
-        ),
-        Err(err) => HttpResponse::InternalServerError().json(json!({ "error": err.to_string() })),
+pub async fn read_user(
+    caller: AuthenticatedUser,
+    path: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let owner = User::for_caller(&caller).await?;
+    if owner.id != *path {
+        return Err(ApiError::Forbidden("users can only read themselves".to_string()));
     }
+    let populated = owner.populate(&["todos"]).await?;
+    Ok(HttpResponse::Ok().json(populated.normalize()))
 }
@@ -0,0 +1,43 @@
+use anyhow::{bail, Result};
+use aws_rust::{
+    config::Env,
+    migrations::{self, registry},
+};
+use tracing_subscriber::FmtSubscriber;
+
+// usage: migrate [up | down [count] | status]
+#[tokio::main]
+async fn main() -> Result<()> {
+    let log_level = Env::global()?.log_level;
+    let subscriber = FmtSubscriber::builder().with_max_level(log_level).finish();
+    tracing::subscriber::set_global_default(subscriber)?;
+    let registered = registry::migrations();
+    let mut args = std::env::args().skip(1);
+    match args.next().as_deref() {
+        None | Some("up") => {
+            let applied = migrations::up(&registered).await?;
+            println!("applied {} migration(s)", applied.len());
+            for name in applied {
+                println!("  {name}");
+            }
+        }
+        Some("down") => {
+            let count = args.next().map_or(Ok(1), |count| count.parse())?;
+            let reverted = migrations::down(&registered, count).await?;
+            println!("reverted {} migration(s)", reverted.len());
+            for name in reverted {
+                println!("  {name}");
+            }
+        }
+        Some("status") => {
+            for migration in migrations::status(&registered).await? {
+                let applied = migration
+                    .applied_at
+                    .map_or("pending".to_string(), |at| format!("applied {at}"));
+                println!("{:>4} {} ({applied})", migration.version, migration.name);
+            }
+        }
+        Some(other) => bail!("unknown command {other}, expected up, down [count] or status"),
+    }
+    Ok(())
+}
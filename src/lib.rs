@@ -1,4 +1,10 @@
+// lets `#[derive(Model)]` refer to this crate as `aws_rust` from inside it too
+extern crate self as aws_rust;
+
+pub mod migrations;
+
 pub mod types {
+    use actix_web::HttpRequest;
     use anyhow::Result;
     use lambda_http::{http::StatusCode, Response};
     use serde::{Deserialize, Serialize};
@@ -22,228 +28,3275 @@ pub mod types {
     }
 
     impl ResponseHelper for Message {}
+
+    /// `Link` header pointing at the neighbouring pages of the current request,
+    /// keeping every other query parameter as it was
+    pub fn pagination_links(req: &HttpRequest, page: u64, pages: u64) -> Option<String> {
+        let params =
+            serde_urlencoded::from_str::<Vec<(String, String)>>(req.query_string()).ok()?;
+        let link = |page: u64, rel: &str| {
+            let mut params = params
+                .iter()
+                .filter(|(key, _)| key != "page")
+                .cloned()
+                .collect::<Vec<_>>();
+            params.push(("page".to_string(), page.to_string()));
+            let query = serde_urlencoded::to_string(params).ok()?;
+            Some(format!("<{}?{query}>; rel=\"{rel}\"", req.path()))
+        };
+        let mut links = vec![];
+        if page < pages {
+            links.extend(link(page + 1, "next"));
+        }
+        if page > 1 {
+            links.extend(link((page - 1).min(pages.max(1)), "prev"));
+        }
+        (!links.is_empty()).then(|| links.join(", "))
+    }
 }
 
 pub mod config {
+    use std::{
+        fmt::{self, Display},
+        str::FromStr,
+    };
+
+    use once_cell::sync::OnceCell;
+
+    static ENV: OnceCell<Env> = OnceCell::new();
+
+    #[derive(Debug, Clone)]
+    pub struct Features {
+        /// mounts the `/api/developer` routes
+        pub developer_routes: bool,
+        /// requires an `x-api-key` on every request outside health and admin routes
+        pub api_keys: bool,
+        /// applies the per-route rate limits
+        pub rate_limit: bool,
+        /// mounts the password reset routes, which need a notifier that delivers tokens
+        pub password_reset: bool,
+    }
+
+    /// how notifications such as password reset tokens reach users
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum NotifierKind {
+        /// appends messages to a local file, for development
+        Outbox,
+        /// logs that a message was sent without its contents
+        Log,
+    }
+
+    impl NotifierKind {
+        /// whether users can read what it sends; the outbox is read on the machine it runs on
+        pub const fn delivers(self) -> bool {
+            matches!(self, Self::Outbox)
+        }
+    }
+
+    impl FromStr for NotifierKind {
+        type Err = String;
+
+        fn from_str(value: &str) -> Result<Self, Self::Err> {
+            match value {
+                "outbox" => Ok(Self::Outbox),
+                "log" => Ok(Self::Log),
+                other => Err(format!("expected outbox or log, got {other}")),
+            }
+        }
+    }
+
+    /// where rate limit and api key throttle counters live; memory is per instance, mongo is
+    /// shared by every instance
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum RateLimitStore {
+        Memory,
+        Mongo,
+    }
+
+    impl FromStr for RateLimitStore {
+        type Err = String;
+
+        fn from_str(value: &str) -> Result<Self, Self::Err> {
+            match value {
+                "memory" => Ok(Self::Memory),
+                "mongo" => Ok(Self::Mongo),
+                other => Err(format!("expected memory or mongo, got {other}")),
+            }
+        }
+    }
+
+    #[derive(Debug, Clone)]
     pub struct Env {
+        pub stage: String,
         pub log_level: tracing::Level,
+        pub host: String,
+        pub port: u16,
         pub mongo_uri: String,
+        pub mongo_pool_size: u32,
+        pub database_url: String,
+        pub mysql_pool_size: u32,
+        pub cors_origins: Vec<String>,
+        pub cursor_secret: String,
+        pub jwt_secret: String,
+        pub jwt_expiry_seconds: i64,
+        pub admin_api_key: Option<String>,
+        pub rate_limit_store: RateLimitStore,
+        pub notifier: NotifierKind,
+        pub outbox_path: String,
+        /// days a soft deleted document is kept before it can be purged
+        pub soft_delete_retention_days: i64,
+        pub features: Features,
+    }
+
+    #[derive(Debug)]
+    pub struct ConfigError {
+        pub problems: Vec<String>,
+    }
+
+    impl Display for ConfigError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "invalid configuration: {}", self.problems.join(", "))
+        }
+    }
+
+    impl std::error::Error for ConfigError {}
+
+    /// collects every missing or invalid key instead of stopping at the first
+    #[derive(Default)]
+    struct Reader {
+        problems: Vec<String>,
     }
 
-    impl Default for Env {
-        fn default() -> Self {
-            if cfg!(debug_assertions) {
-                use dotenv::dotenv;
-                dotenv().ok();
+    impl Reader {
+        fn required(&mut self, key: &str) -> String {
+            match std::env::var(key) {
+                Ok(value) if !value.trim().is_empty() => value.trim().to_string(),
+                _ => {
+                    self.problems.push(format!("{key} is required"));
+                    String::new()
+                }
             }
-            Self {
-                log_level: std::env::var("LOG_LEVEL").map_or(tracing::Level::ERROR, |found| {
-                    match found.to_uppercase().as_ref() {
-                        "INFO" => tracing::Level::INFO,
-                        "DEBUG" => tracing::Level::DEBUG,
-                        "WARN" => tracing::Level::WARN,
-                        "TRACE" => tracing::Level::TRACE,
-                        _ => tracing::Level::ERROR,
-                    }
+        }
+
+        fn optional(&mut self, key: &str, default: &str) -> String {
+            std::env::var(key).map_or(default.to_string(), |value| value.trim().to_string())
+        }
+
+        fn parse<T: FromStr>(&mut self, key: &str, default: T) -> T
+        where
+            T::Err: Display,
+        {
+            match std::env::var(key) {
+                Ok(value) => value.trim().parse().unwrap_or_else(|err| {
+                    self.problems.push(format!("{key} is invalid: {err}"));
+                    default
                 }),
-                mongo_uri: std::env::var("MONGO_URI").map_or(
-                    "mongodb://localhost:27017/rust-aws-local".to_string(),
-                    |uri| uri,
+                Err(_) => default,
+            }
+        }
+
+        fn list(&mut self, key: &str) -> Vec<String> {
+            std::env::var(key).map_or(vec![], |value| {
+                value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|item| !item.is_empty())
+                    .map(ToString::to_string)
+                    .collect()
+            })
+        }
+    }
+
+    impl Env {
+        /// reads the process environment, falling back to `.env.<stage>` and then `.env`
+        /// when present, and reports every missing or invalid key at once
+        pub fn load() -> Result<Self, ConfigError> {
+            let stage = std::env::var("STAGE").unwrap_or_else(|_| "dev".to_string());
+            dotenv::from_filename(format!(".env.{stage}")).ok();
+            dotenv::dotenv().ok();
+            let mut reader = Reader::default();
+            let on_lambda = lambda_web::is_running_on_lambda();
+            let notifier = reader.parse(
+                "NOTIFIER",
+                if stage == "dev" && !on_lambda {
+                    NotifierKind::Outbox
+                } else {
+                    NotifierKind::Log
+                },
+            );
+            let env = Self {
+                log_level: reader.parse("LOG_LEVEL", tracing::Level::ERROR),
+                host: reader.optional("BIND_HOST", "0.0.0.0"),
+                port: reader.parse("PORT", 3000),
+                mongo_uri: reader.required("MONGO_URI"),
+                mongo_pool_size: reader.parse("MONGO_POOL_SIZE", 10),
+                database_url: reader.required("DATABASE_URL"),
+                mysql_pool_size: reader.parse("MYSQL_POOL_SIZE", 5),
+                cors_origins: reader.list("CORS_ORIGINS"),
+                cursor_secret: reader.required("CURSOR_SECRET"),
+                jwt_secret: reader.required("JWT_SECRET"),
+                jwt_expiry_seconds: reader.parse("JWT_EXPIRY_SECONDS", 3600),
+                admin_api_key: Some(reader.optional("ADMIN_API_KEY", ""))
+                    .filter(|key| !key.is_empty()),
+                rate_limit_store: reader.parse(
+                    "RATE_LIMIT_STORE",
+                    if stage == "dev" {
+                        RateLimitStore::Memory
+                    } else {
+                        RateLimitStore::Mongo
+                    },
                 ),
+                notifier,
+                outbox_path: reader.optional("OUTBOX_PATH", ".outbox/messages.jsonl"),
+                soft_delete_retention_days: reader.parse("SOFT_DELETE_RETENTION_DAYS", 30),
+                features: Features {
+                    developer_routes: reader.parse("FEATURE_DEVELOPER_ROUTES", stage != "prod"),
+                    api_keys: reader.parse("FEATURE_API_KEYS", stage != "dev"),
+                    rate_limit: reader.parse("FEATURE_RATE_LIMIT", true),
+                    password_reset: reader.parse(
+                        "FEATURE_PASSWORD_RESET",
+                        stage != "prod" && notifier.delivers(),
+                    ),
+                },
+                stage,
+            };
+            // reset tokens nobody receives would only lock users into a dead end
+            if env.features.password_reset && !env.notifier.delivers() {
+                reader.problems.push(
+                    "FEATURE_PASSWORD_RESET needs a NOTIFIER that delivers messages".to_string(),
+                );
+            }
+            // lambda only lets a function write to /tmp, which is gone with the instance
+            if env.notifier == NotifierKind::Outbox && on_lambda {
+                reader.problems.push(
+                    "NOTIFIER outbox writes a local file, which lambda can't keep".to_string(),
+                );
+            }
+            if reader.problems.is_empty() {
+                return Ok(env);
             }
+            Err(ConfigError {
+                problems: reader.problems,
+            })
+        }
+
+        /// the process wide config, loaded once on first use
+        pub fn global() -> Result<&'static Self, ConfigError> {
+            ENV.get_or_try_init(Self::load)
         }
     }
 }
 
-pub mod database {
-    use std::fmt::Debug;
+pub mod errors {
+    use std::fmt;
 
-    use anyhow::Result;
-    use async_once::AsyncOnce;
-    use async_trait::async_trait;
-    use bson::{doc, Document};
-    use futures::stream::TryStreamExt;
-    use lazy_static::lazy_static;
-    use mongodb::{
-        error::Error as MongoError,
-        options::{ClientOptions, FindOneOptions, FindOptions},
-        results::{CreateIndexesResult, UpdateResult},
-        Client, Collection, Database,
+    use actix_web::{
+        http::{header, StatusCode},
+        HttpResponse, ResponseError,
     };
-    use nanoid::nanoid;
-    use serde::{de::DeserializeOwned, Deserialize, Serialize};
+    use mongodb::error::{Error as MongoError, ErrorKind, WriteFailure};
+    use prisma_client_rust::{
+        prisma_errors::query_engine::{RecordNotFound, UniqueKeyViolation},
+        QueryError,
+    };
+    use serde::Serialize;
 
-    use crate::config::Env;
+    use crate::database::{generate_nanoid, DatabaseUnavailable, InvalidCursor};
 
-    lazy_static! {
-        pub static ref DATABASE: AsyncOnce<Database> = AsyncOnce::new(async {
-            let Env { mongo_uri, .. } = Env::default();
-            let client_options = ClientOptions::parse(mongo_uri).await.map_or_else(
-                |err| {
-                    tracing::error!("error parsing client options {err:?}");
-                    std::process::exit(1);
+    const DUPLICATE_KEY: i32 = 11000;
+    const RETRY_AFTER_SECONDS: u32 = 5;
+
+    /// the duplicate key message when `err` is an E11000 unique index violation
+    fn duplicate_key_message(err: &MongoError) -> Option<&str> {
+        match err.kind.as_ref() {
+            ErrorKind::Write(WriteFailure::WriteError(write)) if write.code == DUPLICATE_KEY => {
+                Some(write.message.as_str())
+            }
+            ErrorKind::Command(command) if command.code == DUPLICATE_KEY => {
+                Some(command.message.as_str())
+            }
+            _ => None,
+        }
+    }
+
+    pub fn is_duplicate_key(err: &MongoError) -> bool {
+        duplicate_key_message(err).is_some()
+    }
+
+    #[derive(Debug, Clone, Serialize)]
+    pub struct FieldError {
+        pub field: String,
+        pub message: String,
+    }
+
+    #[derive(Debug)]
+    pub enum ApiError {
+        Unauthorized(String),
+        Forbidden(String),
+        NotFound(String),
+        Conflict { field: Option<String> },
+        Validation(Vec<FieldError>),
+        TooManyRequests { message: String, retry_after: u64 },
+        Unavailable(String),
+        Internal { correlation_id: String },
+    }
+
+    #[derive(Serialize)]
+    struct ErrorBody<'a> {
+        code: &'a str,
+        error: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        field: Option<&'a str>,
+        #[serde(skip_serializing_if = "<[_]>::is_empty")]
+        fields: &'a [FieldError],
+        #[serde(skip_serializing_if = "Option::is_none")]
+        correlation_id: Option<&'a str>,
+    }
+
+    impl ApiError {
+        pub fn validation(field: &str, message: impl ToString) -> Self {
+            Self::Validation(vec![FieldError {
+                field: field.to_string(),
+                message: message.to_string(),
+            }])
+        }
+
+        /// logs the underlying error and hides it behind an opaque correlation id
+        pub fn internal(err: impl fmt::Debug) -> Self {
+            let correlation_id = generate_nanoid();
+            tracing::error!("[{correlation_id}] {err:?}");
+            Self::Internal { correlation_id }
+        }
+
+        pub const fn code(&self) -> &'static str {
+            match self {
+                Self::Unauthorized(_) => "unauthorized",
+                Self::Forbidden(_) => "forbidden",
+                Self::NotFound(_) => "not_found",
+                Self::Conflict { .. } => "conflict",
+                Self::Validation(_) => "validation_failed",
+                Self::TooManyRequests { .. } => "rate_limited",
+                Self::Unavailable(_) => "service_unavailable",
+                Self::Internal { .. } => "internal_error",
+            }
+        }
+    }
+
+    impl fmt::Display for ApiError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Self::Unauthorized(message) | Self::Forbidden(message) | Self::NotFound(message) => {
+                    write!(f, "{message}")
+                }
+                Self::Conflict { field: Some(field) } => write!(f, "{field} already exists"),
+                Self::Conflict { field: None } => write!(f, "resource already exists"),
+                Self::Validation(_) => write!(f, "request validation failed"),
+                Self::TooManyRequests { message, .. } => write!(f, "{message}"),
+                Self::Unavailable(service) => {
+                    write!(f, "{service} is temporarily unavailable, try again shortly")
+                }
+                Self::Internal { .. } => write!(f, "internal server error"),
+            }
+        }
+    }
+
+    impl std::error::Error for ApiError {}
+
+    impl ResponseError for ApiError {
+        fn status_code(&self) -> StatusCode {
+            match self {
+                Self::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+                Self::Forbidden(_) => StatusCode::FORBIDDEN,
+                Self::NotFound(_) => StatusCode::NOT_FOUND,
+                Self::Conflict { .. } => StatusCode::CONFLICT,
+                Self::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+                Self::TooManyRequests { .. } => StatusCode::TOO_MANY_REQUESTS,
+                Self::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+                Self::Internal { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+            }
+        }
+
+        fn error_response(&self) -> HttpResponse {
+            let body = ErrorBody {
+                code: self.code(),
+                error: self.to_string(),
+                field: match self {
+                    Self::Conflict { field } => field.as_deref(),
+                    _ => None,
                 },
-                |opts| opts,
-            );
-            let client = Client::with_options(client_options).map_or_else(
-                |err| {
-                    tracing::error!("error connecting client: {err:?}");
-                    std::process::exit(1);
+                fields: match self {
+                    Self::Validation(fields) => fields,
+                    _ => &[],
                 },
-                |client| client,
-            );
-            client.default_database().map_or_else(
-                || {
-                    tracing::error!("no default database found");
-                    std::process::exit(1);
+                correlation_id: match self {
+                    Self::Internal { correlation_id } => Some(correlation_id),
+                    _ => None,
                 },
-                |db| db,
-            )
-        });
+            };
+            let mut response = HttpResponse::build(self.status_code());
+            match self {
+                Self::Unauthorized(_) => {
+                    response.insert_header((header::WWW_AUTHENTICATE, "Bearer"));
+                }
+                Self::TooManyRequests { retry_after, .. } => {
+                    response.insert_header((header::RETRY_AFTER, *retry_after));
+                }
+                Self::Unavailable(_) => {
+                    response.insert_header((header::RETRY_AFTER, RETRY_AFTER_SECONDS));
+                }
+                _ => (),
+            }
+            response.json(body)
+        }
     }
 
-    pub fn generate_nanoid() -> String {
-        // ~2 million years needed, in order to have a 1% probability of at least one collision.
-        // https://zelark.github.io/nano-id-cc/
-        let alphabet = [
-            'A', 'B', 'C', 'D', 'E', 'F', 'G', 'H', 'I', 'J', 'K', 'L', 'M', 'N', 'O', 'P', 'Q',
-            'R', 'S', 'T', 'U', 'V', 'W', 'X', 'Y', 'Z',
-        ];
-        nanoid!(20, &alphabet)
+    /// pulls the offending field out of a message like
+    /// `E11000 duplicate key error collection: db.users index: username_1 dup key: { username: "jude" }`
+    fn duplicate_key_field(message: &str) -> Option<String> {
+        if let Some((_, key)) = message.split_once("dup key: {") {
+            if let Some((field, _)) = key.split_once(':') {
+                return Some(field.trim().trim_matches('"').to_string());
+            }
+        }
+        let (_, index) = message.split_once("index: ")?;
+        let index = index.split_whitespace().next()?;
+        Some(index.rsplit_once('_').map_or(index, |(field, _)| field).to_string())
     }
 
-    #[derive(Serialize, Default)]
-    pub struct ListQueryOptions {
-        pub limit: Option<i64>,
-        pub skip: Option<u64>,
-        pub sort: Option<Document>,
-        pub projection: Option<Document>,
+    impl From<MongoError> for ApiError {
+        fn from(err: MongoError) -> Self {
+            match duplicate_key_message(&err) {
+                Some(message) => Self::Conflict {
+                    field: duplicate_key_field(message),
+                },
+                None => Self::internal(err),
+            }
+        }
     }
 
-    #[derive(Serialize, Default)]
-    pub struct FindQueryOptions {
-        pub projection: Option<Document>,
+    impl From<DatabaseUnavailable> for ApiError {
+        fn from(err: DatabaseUnavailable) -> Self {
+            tracing::error!("{err}");
+            Self::Unavailable(err.database.to_string())
+        }
     }
 
-    #[derive(Debug, Deserialize, Serialize)]
-    pub enum Ref<T> {
-        Id(String),
-        Document(T),
+    impl From<QueryError> for ApiError {
+        fn from(err: QueryError) -> Self {
+            if err.is_prisma_error::<RecordNotFound>() {
+                return Self::NotFound("record not found".to_string());
+            }
+            if err.is_prisma_error::<UniqueKeyViolation>() {
+                let field = match &err {
+                    QueryError::Execute(execute) => execute
+                        .as_known()
+                        .and_then(|known| match &known.meta["target"] {
+                            serde_json::Value::String(target) => Some(target.clone()),
+                            serde_json::Value::Array(targets) => targets
+                                .first()
+                                .and_then(|target| target.as_str().map(ToString::to_string)),
+                            _ => None,
+                        }),
+                    _ => None,
+                };
+                return Self::Conflict { field };
+            }
+            Self::internal(err)
+        }
     }
 
-    #[async_trait]
-    pub trait Model: Unpin + Serialize + Sized + Send + Sync + DeserializeOwned {
-        fn collection_name<'a>() -> &'a str;
-        async fn create_indexes() -> Result<Option<CreateIndexesResult>>;
-
-        async fn collection() -> Collection<Self> {
-            let name = Self::collection_name();
-            DATABASE.get().await.collection::<Self>(name)
+    impl From<anyhow::Error> for ApiError {
+        fn from(err: anyhow::Error) -> Self {
+            let err = match err.downcast::<Self>() {
+                Ok(api) => return api,
+                Err(err) => err,
+            };
+            let err = match err.downcast::<MongoError>() {
+                Ok(mongo) => return mongo.into(),
+                Err(err) => err,
+            };
+            let err = match err.downcast::<QueryError>() {
+                Ok(query) => return query.into(),
+                Err(err) => err,
+            };
+            if let Some(unavailable) = err.downcast_ref::<DatabaseUnavailable>() {
+                tracing::error!("{unavailable}");
+                return Self::Unavailable(unavailable.database.to_string());
+            }
+            if let Some(cursor) = err.downcast_ref::<InvalidCursor>() {
+                return Self::validation("cursor", cursor);
+            }
+            Self::internal(err)
         }
+    }
+}
 
-        async fn count() -> Result<u64> {
-            let count = Self::collection()
-                .await
-                .estimated_document_count(None)
-                .await?;
-            Ok(count)
+pub mod validation {
+    use std::{fmt::Display, ops::Deref};
+
+    use actix_web::{dev::Payload, web, FromRequest, HttpRequest};
+    use futures::future::LocalBoxFuture;
+    use serde::de::DeserializeOwned;
+
+    use crate::errors::{ApiError, FieldError};
+
+    pub trait Validate {
+        /// trims and normalizes input in place, runs before `validate`
+        fn normalize(&mut self) {}
+        fn validate(&self, validator: &mut Validator);
+    }
+
+    #[derive(Default)]
+    pub struct Validator {
+        prefix: String,
+        errors: Vec<FieldError>,
+    }
+
+    pub fn is_email(value: &str) -> bool {
+        let Some((local, domain)) = value.split_once('@') else {
+            return false;
+        };
+        !local.is_empty()
+            && !domain.contains('@')
+            && domain.contains('.')
+            && !domain.starts_with('.')
+            && !domain.ends_with('.')
+            && !value.chars().any(char::is_whitespace)
+    }
+
+    /// trims a string in place without reallocating when nothing changes
+    pub fn trim(value: &mut String) {
+        let trimmed = value.trim();
+        if trimmed.len() != value.len() {
+            *value = trimmed.to_string();
         }
+    }
 
-        async fn save(&self) -> Result<&Self> {
-            Self::collection().await.insert_one(self, None).await?;
-            Ok(self)
+    impl Validator {
+        pub fn check<T: Validate>(value: &T) -> Result<(), ApiError> {
+            let mut validator = Self::default();
+            value.validate(&mut validator);
+            if validator.errors.is_empty() {
+                return Ok(());
+            }
+            Err(ApiError::Validation(validator.errors))
         }
 
-        async fn update_one(filter: Document, updates: Document) -> Result<UpdateResult> {
-            let updated = Self::collection()
-                .await
-                .update_one(filter, updates, None)
-                .await?;
-            Ok(updated)
+        pub fn error(&mut self, field: &str, message: impl ToString) -> &mut Self {
+            self.errors.push(FieldError {
+                field: format!("{}{field}", self.prefix),
+                message: message.to_string(),
+            });
+            self
         }
 
-        async fn update_many(filter: Document, updates: Document) -> Result<UpdateResult> {
-            let updated = Self::collection()
-                .await
-                .update_many(filter, updates, None)
-                .await?;
-            Ok(updated)
+        pub fn length(&mut self, field: &str, value: &str, min: usize, max: usize) -> &mut Self {
+            let length = value.chars().count();
+            if length == 0 && min > 0 {
+                return self.error(field, "is required");
+            }
+            if length < min {
+                return self.error(field, format!("must be at least {min} characters"));
+            }
+            if length > max {
+                return self.error(field, format!("must be at most {max} characters"));
+            }
+            self
         }
 
-        async fn read(
-            filter: Option<Document>,
-            options: Option<FindQueryOptions>,
-        ) -> Result<Option<Self>, MongoError> {
-            let opts = match options {
-                Some(opts) => {
-                    let options = FindOneOptions::builder()
-                        .projection(opts.projection)
-                        .build();
-                    Some(options)
-                }
-                None => None,
-            };
-            Self::collection().await.find_one(filter, opts).await
+        pub fn email(&mut self, field: &str, value: &str) -> &mut Self {
+            if value.is_empty() {
+                return self.error(field, "is required");
+            }
+            if !is_email(value) || value.len() > 254 {
+                return self.error(field, "must be a valid email address");
+            }
+            self
         }
 
-        async fn list(
-            filter: Option<Document>,
-            options: Option<ListQueryOptions>,
-        ) -> Result<Vec<Self>, MongoError> {
-            let opts = match options {
-                Some(opts) => {
-                    let options = FindOptions::builder()
-                        .skip(opts.skip)
-                        .limit(opts.limit)
-                        .sort(opts.sort)
-                        .projection(opts.projection)
-                        .build();
-                    Some(options)
-                }
-                None => None,
-            };
-            let mut result = Self::collection().await.find(filter, opts).await?;
-            let mut docs = vec![];
-            while let Some(doc) = result.try_next().await? {
-                docs.push(doc);
+        pub fn range<T: PartialOrd + Display>(
+            &mut self,
+            field: &str,
+            value: T,
+            min: T,
+            max: T,
+        ) -> &mut Self {
+            if value < min || value > max {
+                return self.error(field, format!("must be between {min} and {max}"));
             }
-            Ok(docs)
+            self
         }
 
-        async fn aggregate(pipeline: &[bson::Document]) -> Result<Vec<Self>> {
-            let pipeline = pipeline.to_owned();
-            let mut results = Self::collection().await.aggregate(pipeline, None).await?;
-            let mut docs = vec![];
-            while let Some(doc) = results.try_next().await? {
-                let document = bson::from_document(doc)?;
-                docs.push(document);
+        pub fn nested<T: Validate>(&mut self, field: &str, value: &T) -> &mut Self {
+            let outer = self.prefix.clone();
+            self.prefix = format!("{outer}{field}.");
+            value.validate(self);
+            self.prefix = outer;
+            self
+        }
+    }
+
+    /// json body extractor that normalizes and validates the payload before the handler runs
+    pub struct Valid<T>(pub T);
+
+    impl<T> Valid<T> {
+        pub fn into_inner(self) -> T {
+            self.0
+        }
+    }
+
+    impl<T> Deref for Valid<T> {
+        type Target = T;
+
+        fn deref(&self) -> &T {
+            &self.0
+        }
+    }
+
+    impl<T: DeserializeOwned + Validate + 'static> FromRequest for Valid<T> {
+        type Error = actix_web::Error;
+        type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+        fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+            let json = web::Json::<T>::from_request(req, payload);
+            Box::pin(async move {
+                let mut value = json.await?.into_inner();
+                value.normalize();
+                Validator::check(&value)?;
+                Ok(Self(value))
+            })
+        }
+    }
+}
+
+pub mod auth {
+    use actix_web::{
+        dev::Payload,
+        http::header::{self, HeaderMap},
+        web, FromRequest, HttpMessage, HttpRequest,
+    };
+    use anyhow::Result;
+    use async_trait::async_trait;
+    use futures::future::{ready, LocalBoxFuture, Ready};
+    use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+    use serde::{Deserialize, Serialize};
+    use sha2::{Digest, Sha256};
+
+    use crate::{config::Env, errors::ApiError};
+
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    pub struct Claims {
+        pub sub: String,
+        pub email: String,
+        pub iat: i64,
+        pub exp: i64,
+        /// the account's token version when this was issued
+        #[serde(default)]
+        pub ver: i32,
+    }
+
+    #[derive(Debug, Serialize)]
+    pub struct AccessToken {
+        pub access_token: String,
+        pub token_type: &'static str,
+        pub expires_in: i64,
+    }
+
+    /// signs an HS256 access token for `subject` at its current token `version` using the
+    /// configured secret and expiry
+    pub fn issue_token(env: &Env, subject: &str, email: &str, version: i32) -> Result<AccessToken> {
+        let now = chrono::Utc::now().timestamp();
+        let claims = Claims {
+            sub: subject.to_string(),
+            email: email.to_string(),
+            iat: now,
+            exp: now + env.jwt_expiry_seconds,
+            ver: version,
+        };
+        let access_token = encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(env.jwt_secret.as_bytes()),
+        )?;
+        Ok(AccessToken {
+            access_token,
+            token_type: "Bearer",
+            expires_in: env.jwt_expiry_seconds,
+        })
+    }
+
+    /// looks up the token version of an account, which is bumped whenever its password
+    /// changes so tokens issued before stop working
+    #[async_trait]
+    pub trait TokenVersions: Send + Sync {
+        /// the current version, or `None` when the account no longer exists
+        async fn current(&self, subject: &str) -> Result<Option<i32>>;
+    }
+
+    /// checks the signature and expiry of an access token and returns its claims, without
+    /// asking whether it has been revoked since
+    pub fn decode_token(env: &Env, token: &str) -> Result<Claims> {
+        let data = decode::<Claims>(
+            token,
+            &DecodingKey::from_secret(env.jwt_secret.as_bytes()),
+            &Validation::default(),
+        )?;
+        Ok(data.claims)
+    }
+
+    /// checks the signature and expiry of an access token, and that it was issued after the
+    /// account's last password change, and returns its claims
+    pub async fn verify_token(
+        env: &Env,
+        versions: &dyn TokenVersions,
+        token: &str,
+    ) -> Result<Claims, ApiError> {
+        let invalid = || ApiError::Unauthorized("invalid or expired token".to_string());
+        let claims = decode_token(env, token).map_err(|_| invalid())?;
+        match versions.current(&claims.sub).await? {
+            Some(version) if version == claims.ver => Ok(claims),
+            // issued before the password changed, or for a deleted account
+            _ => Err(invalid()),
+        }
+    }
+
+    pub fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+        headers
+            .get(header::AUTHORIZATION)?
+            .to_str()
+            .ok()?
+            .strip_prefix("Bearer ")
+            .map(str::trim)
+    }
+
+    /// extractor for handlers that require a valid bearer token; rejects with 401 otherwise
+    #[derive(Debug, Clone)]
+    pub struct AuthenticatedUser {
+        pub id: String,
+        pub email: String,
+    }
+
+    impl AuthenticatedUser {
+        async fn from_token(
+            env: &Env,
+            versions: &dyn TokenVersions,
+            token: Option<&str>,
+        ) -> Result<Self, ApiError> {
+            let token =
+                token.ok_or_else(|| ApiError::Unauthorized("missing bearer token".to_string()))?;
+            let claims = verify_token(env, versions, token).await?;
+            Ok(Self {
+                id: claims.sub,
+                email: claims.email,
+            })
+        }
+    }
+
+    impl FromRequest for AuthenticatedUser {
+        type Error = ApiError;
+        type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+        fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+            // kept on the request so middleware and the handler share one version lookup
+            if let Some(user) = req.extensions().get::<Self>() {
+                return Box::pin(ready(Ok(user.clone())));
             }
-            Ok(docs)
+            let req = req.clone();
+            Box::pin(async move {
+                let env = req.app_data::<web::Data<Env>>();
+                let versions = req.app_data::<web::Data<dyn TokenVersions>>();
+                let (Some(env), Some(versions)) = (env, versions) else {
+                    return Err(ApiError::internal(
+                        "config or token versions are not registered as app data",
+                    ));
+                };
+                let user =
+                    Self::from_token(env, versions.as_ref(), bearer_token(req.headers())).await?;
+                req.extensions_mut().insert(user.clone());
+                Ok(user)
+            })
         }
+    }
 
-        async fn read_populate<T: DeserializeOwned>(
-            query: Document,
-            fields: &[&str],
-        ) -> Result<Option<T>> {
-            let mut pipeline = vec![doc! { "$match": query }];
-            for field in fields {
-                pipeline.push(doc! {
-                    "$lookup": {
-                        "from": field,
-                        "localField": field,
-                        "foreignField": "_id",
-                        "as": field
-                    }
-                });
+    /// extractor for the admin api, matches `x-admin-key` against `ADMIN_API_KEY`
+    #[derive(Debug, Clone, Copy)]
+    pub struct Admin;
+
+    impl Admin {
+        fn from_headers(env: &Env, headers: &HeaderMap) -> Result<Self, ApiError> {
+            let Some(expected) = env.admin_api_key.as_deref() else {
+                return Err(ApiError::Forbidden("admin api is disabled".to_string()));
+            };
+            let provided = headers
+                .get("x-admin-key")
+                .and_then(|value| value.to_str().ok())
+                .ok_or_else(|| ApiError::Unauthorized("missing admin key".to_string()))?;
+            // comparing digests keeps the comparison time independent of the shared prefix
+            if Sha256::digest(provided.as_bytes()) != Sha256::digest(expected.as_bytes()) {
+                return Err(ApiError::Forbidden("invalid admin key".to_string()));
             }
-            pipeline.push(doc! { "$limit": 1 });
-            let mut results = Self::collection().await.aggregate(pipeline, None).await?;
-            let first = results.try_next().await?;
-            if let Some(doc) = first {
-                let document = bson::from_document::<T>(doc)?;
-                return Ok(Some(document));
+            Ok(Self)
+        }
+    }
+
+    impl FromRequest for Admin {
+        type Error = ApiError;
+        type Future = Ready<Result<Self, Self::Error>>;
+
+        fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+            let admin = match req.app_data::<web::Data<Env>>() {
+                Some(env) => Self::from_headers(env, req.headers()),
+                None => Err(ApiError::internal("config is not registered as app data")),
+            };
+            ready(admin)
+        }
+    }
+}
+
+pub mod notify {
+    use std::{path::PathBuf, sync::Arc};
+
+    use anyhow::Result;
+    use async_trait::async_trait;
+    use serde::Serialize;
+    use tokio::{fs, io::AsyncWriteExt};
+
+    use crate::config::{Env, NotifierKind};
+
+    #[derive(Debug, Clone, Serialize)]
+    pub struct Notification {
+        pub to: String,
+        pub subject: String,
+        pub body: String,
+    }
+
+    #[async_trait]
+    pub trait Notifier: Send + Sync {
+        async fn send(&self, notification: &Notification) -> Result<()>;
+    }
+
+    /// appends each notification as a json line so they can be read back locally
+    pub struct Outbox {
+        path: PathBuf,
+    }
+
+    impl Outbox {
+        pub fn new(path: impl Into<PathBuf>) -> Self {
+            Self { path: path.into() }
+        }
+    }
+
+    #[async_trait]
+    impl Notifier for Outbox {
+        async fn send(&self, notification: &Notification) -> Result<()> {
+            if let Some(parent) = self.path.parent() {
+                fs::create_dir_all(parent).await?;
+            }
+            let mut line = serde_json::to_vec(&serde_json::json!({
+                "sent_at": chrono::Utc::now(),
+                "notification": notification,
+            }))?;
+            line.push(b'\n');
+            let mut outbox = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)
+                .await?;
+            outbox.write_all(&line).await?;
+            Ok(())
+        }
+    }
+
+    /// records that a notification went out, leaving the body out of the logs
+    pub struct Log;
+
+    #[async_trait]
+    impl Notifier for Log {
+        async fn send(&self, notification: &Notification) -> Result<()> {
+            tracing::info!(
+                "notification {:?} sent to {}",
+                notification.subject,
+                notification.to
+            );
+            Ok(())
+        }
+    }
+
+    pub fn from_env(env: &Env) -> Arc<dyn Notifier> {
+        match env.notifier {
+            NotifierKind::Outbox => Arc::new(Outbox::new(&env.outbox_path)),
+            NotifierKind::Log => Arc::new(Log),
+        }
+    }
+}
+
+pub mod query {
+    use std::{fmt, marker::PhantomData};
+
+    use bson::{doc, Bson, Document};
+
+    use crate::database::{Model, Ref};
+
+    pub use aws_rust_macros::Fields;
+
+    /// values that can be compared with or stored in a field of type `T`
+    pub trait Value<T>: Into<Bson> {}
+
+    impl<T: Into<Bson>> Value<T> for T {}
+    impl<'a, T> Value<T> for &'a T where &'a T: Into<Bson> {}
+    impl Value<String> for &str {}
+    // a reference matches the id field of the document it refers to
+    impl<M: Model + Clone> Value<String> for &Ref<M> {}
+    impl<M: Model> Value<Ref<M>> for String {}
+    impl<M: Model> Value<Ref<M>> for &String {}
+    impl<M: Model> Value<Ref<M>> for &str {}
+
+    /// the path of a field of model `M` that holds a `T`, usually generated by
+    /// `#[derive(Fields)]`
+    pub struct Field<M, T> {
+        path: &'static str,
+        model: PhantomData<fn() -> (M, T)>,
+    }
+
+    impl<M, T> Clone for Field<M, T> {
+        fn clone(&self) -> Self {
+            *self
+        }
+    }
+
+    impl<M, T> Copy for Field<M, T> {}
+
+    impl<M, T> fmt::Debug for Field<M, T> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_tuple("Field").field(&self.path).finish()
+        }
+    }
+
+    impl<M, T> Field<M, T> {
+        pub const fn new(path: &'static str) -> Self {
+            Self {
+                path,
+                model: PhantomData,
+            }
+        }
+
+        pub const fn path(&self) -> &'static str {
+            self.path
+        }
+
+        fn condition(self, operator: &str, value: Bson) -> Filter<M> {
+            Filter::new(doc! { self.path: { operator: value } })
+        }
+
+        fn operation(self, operator: &str, value: Bson) -> Update<M> {
+            Update::new(doc! { operator: { self.path: value } })
+        }
+
+        pub fn eq(self, value: impl Value<T>) -> Filter<M> {
+            Filter::new(doc! { self.path: value.into() })
+        }
+
+        pub fn ne(self, value: impl Value<T>) -> Filter<M> {
+            self.condition("$ne", value.into())
+        }
+
+        /// matches any of `values`
+        pub fn is_in<V: Value<T>>(self, values: impl IntoIterator<Item = V>) -> Filter<M> {
+            let values = values.into_iter().map(Into::into).collect::<Vec<Bson>>();
+            self.condition("$in", values.into())
+        }
+
+        pub fn gt(self, value: impl Value<T>) -> Filter<M> {
+            self.condition("$gt", value.into())
+        }
+
+        pub fn gte(self, value: impl Value<T>) -> Filter<M> {
+            self.condition("$gte", value.into())
+        }
+
+        pub fn lt(self, value: impl Value<T>) -> Filter<M> {
+            self.condition("$lt", value.into())
+        }
+
+        pub fn lte(self, value: impl Value<T>) -> Filter<M> {
+            self.condition("$lte", value.into())
+        }
+
+        pub fn set(self, value: impl Value<T>) -> Update<M> {
+            self.operation("$set", value.into())
+        }
+    }
+
+    impl<M> Field<M, String> {
+        /// matches strings containing `pattern`, with mongo's regex `options` such as `i`
+        pub fn regex(self, pattern: &str, options: &str) -> Filter<M> {
+            Filter::new(doc! { self.path: { "$regex": pattern, "$options": options } })
+        }
+    }
+
+    impl<M, T> Field<M, Option<T>> {
+        pub fn unset(self) -> Update<M> {
+            self.operation("$unset", Bson::String(String::new()))
+        }
+    }
+
+    impl<M, T> Field<M, Vec<T>> {
+        /// matches arrays holding `value`
+        pub fn contains(self, value: impl Value<T>) -> Filter<M> {
+            Filter::new(doc! { self.path: value.into() })
+        }
+
+        pub fn push(self, value: impl Value<T>) -> Update<M> {
+            self.operation("$push", value.into())
+        }
+
+        /// removes every element equal to `value`
+        pub fn pull(self, value: impl Value<T>) -> Update<M> {
+            self.operation("$pull", value.into())
+        }
+    }
+
+    /// a query on model `M`, built from its `Field`s
+    pub struct Filter<M> {
+        document: Document,
+        model: PhantomData<fn() -> M>,
+    }
+
+    impl<M> Filter<M> {
+        const fn new(document: Document) -> Self {
+            Self {
+                document,
+                model: PhantomData,
+            }
+        }
+
+        /// matches documents matching both filters
+        #[must_use]
+        pub fn and(mut self, other: Self) -> Self {
+            if other.document.keys().any(|key| self.document.contains_key(key)) {
+                return Self::new(doc! { "$and": [self.document, other.document] });
+            }
+            self.document.extend(other.document);
+            self
+        }
+
+        pub fn into_document(self) -> Document {
+            self.document
+        }
+    }
+
+    impl<M> Clone for Filter<M> {
+        fn clone(&self) -> Self {
+            Self::new(self.document.clone())
+        }
+    }
+
+    impl<M> fmt::Debug for Filter<M> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            self.document.fmt(f)
+        }
+    }
+
+    /// update operators for model `M`, built from its `Field`s
+    pub struct Update<M> {
+        document: Document,
+        model: PhantomData<fn() -> M>,
+    }
+
+    impl<M> Update<M> {
+        const fn new(document: Document) -> Self {
+            Self {
+                document,
+                model: PhantomData,
+            }
+        }
+
+        /// applies both updates, merging their operators
+        #[must_use]
+        pub fn and(mut self, other: Self) -> Self {
+            for (operator, fields) in other.document {
+                match (self.document.get_mut(&operator), fields) {
+                    (Some(Bson::Document(existing)), Bson::Document(fields)) => {
+                        existing.extend(fields);
+                    }
+                    (_, fields) => {
+                        self.document.insert(operator, fields);
+                    }
+                }
+            }
+            self
+        }
+
+        pub fn into_document(self) -> Document {
+            self.document
+        }
+    }
+
+    impl<M> Clone for Update<M> {
+        fn clone(&self) -> Self {
+            Self::new(self.document.clone())
+        }
+    }
+
+    impl<M> fmt::Debug for Update<M> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            self.document.fmt(f)
+        }
+    }
+
+    /// a filter on model `M`, either typed or a raw document
+    pub trait IntoFilter<M>: Send {
+        fn into_filter(self) -> Document;
+    }
+
+    impl<M> IntoFilter<M> for Document {
+        fn into_filter(self) -> Document {
+            self
+        }
+    }
+
+    impl<M> IntoFilter<M> for Filter<M> {
+        fn into_filter(self) -> Document {
+            self.document
+        }
+    }
+
+    /// an update of model `M`, either typed or a raw document
+    pub trait IntoUpdate<M>: Send {
+        fn into_update(self) -> Document;
+    }
+
+    impl<M> IntoUpdate<M> for Document {
+        fn into_update(self) -> Document {
+            self
+        }
+    }
+
+    impl<M> IntoUpdate<M> for Update<M> {
+        fn into_update(self) -> Document {
+            self.document
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use serde::Serialize;
+
+        use super::*;
+
+        #[derive(Serialize, Fields)]
+        struct Todo {
+            #[serde(rename = "_id")]
+            id: String,
+            task: String,
+            tags: Vec<String>,
+            done_at: Option<String>,
+        }
+
+        #[derive(Serialize, Fields)]
+        #[serde(rename_all = "camelCase")]
+        struct Renamed {
+            created_at: i64,
+            #[serde(rename(serialize = "by", deserialize = "author"))]
+            created_by: String,
+            #[serde(rename(deserialize = "old_name"))]
+            kept_name: String,
+            r#type: String,
+            #[serde(skip)]
+            #[allow(dead_code)]
+            cached: bool,
+        }
+
+        #[derive(Serialize, Fields)]
+        #[serde(rename_all(serialize = "SCREAMING-KEBAB-CASE"))]
+        struct Shouted {
+            created_at: i64,
+        }
+
+        #[test]
+        fn field_paths_follow_serde() {
+            assert_eq!(Todo::ID.path(), "_id");
+            assert_eq!(Renamed::CREATED_AT.path(), "createdAt");
+            assert_eq!(Renamed::CREATED_BY.path(), "by");
+            assert_eq!(Renamed::KEPT_NAME.path(), "keptName");
+            assert_eq!(Renamed::TYPE.path(), "type");
+            assert_eq!(Shouted::CREATED_AT.path(), "CREATED-AT");
+        }
+
+        #[test]
+        fn filters_on_different_fields_merge() {
+            let filter = Todo::TASK.eq("milk").and(Todo::TAGS.contains("home"));
+            assert_eq!(filter.into_document(), doc! { "task": "milk", "tags": "home" });
+        }
+
+        #[test]
+        fn filters_on_the_same_field_are_combined_with_and() {
+            let filter = Todo::TASK
+                .regex("mi", "i")
+                .and(Todo::ID.ne("a"))
+                .and(Todo::TASK.ne("milk"));
+            let expected = doc! {
+                "$and": [
+                    { "task": { "$regex": "mi", "$options": "i" }, "_id": { "$ne": "a" } },
+                    { "task": { "$ne": "milk" } },
+                ]
+            };
+            assert_eq!(filter.into_document(), expected);
+        }
+
+        #[test]
+        fn updates_merge_their_operators() {
+            let update = Todo::TASK
+                .set("milk")
+                .and(Todo::TAGS.push("home"))
+                .and(Todo::DONE_AT.unset())
+                .and(Todo::ID.set("b"));
+            let expected = doc! {
+                "$set": { "task": "milk", "_id": "b" },
+                "$push": { "tags": "home" },
+                "$unset": { "done_at": "" },
+            };
+            assert_eq!(update.into_document(), expected);
+        }
+    }
+}
+
+pub mod database {
+    use std::{
+        collections::HashMap,
+        fmt::{self, Debug},
+        future::Future,
+        time::Duration,
+    };
+
+    use actix_web::{http::header, HttpRequest, HttpResponse};
+    use anyhow::{anyhow, bail, Result};
+    use async_trait::async_trait;
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use bson::{doc, Bson, Document};
+    use futures::{future::BoxFuture, stream::TryStreamExt};
+    use hmac::{Hmac, Mac};
+    use lazy_static::lazy_static;
+    use mongodb::{
+        error::{
+            Error as MongoError, ErrorKind, TRANSIENT_TRANSACTION_ERROR,
+            UNKNOWN_TRANSACTION_COMMIT_RESULT,
+        },
+        options::{
+            ClientOptions, FindOneAndUpdateOptions, FindOneOptions, FindOptions, ReturnDocument,
+            UpdateOptions,
+        },
+        results::{DeleteResult, UpdateResult},
+        Client, ClientSession, Collection, Database, IndexModel,
+    };
+    use nanoid::nanoid;
+    use serde::{de::DeserializeOwned, Deserialize, Serialize};
+    use sha2::Sha256;
+    use tokio::sync::OnceCell;
+
+    use crate::{
+        audit::{Operation, Reach, Trail, MAX_AUDITED_WRITE},
+        config::Env,
+        query::{IntoFilter, IntoUpdate},
+        types::pagination_links,
+    };
+
+    pub use aws_rust_macros::Model;
+
+    type HmacSha256 = Hmac<Sha256>;
+
+    lazy_static! {
+        static ref CLIENT: OnceCell<Client> = OnceCell::new();
+    }
+
+    const CONNECT_MAX_ATTEMPTS: u32 = 3;
+    const CONNECT_BACKOFF: Duration = Duration::from_millis(200);
+    /// how long the drivers wait on a server in one attempt; every attempt plus the backoff
+    /// between them has to fit inside the 6s lambda timeout, or clients get a gateway
+    /// timeout instead of the 503
+    pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+    /// the cap on a whole attempt, for drivers that spend time outside their own timeouts
+    const CONNECT_ATTEMPT_TIMEOUT: Duration = Duration::from_millis(1200);
+
+    /// a data store could not be reached, surfaced to clients as a 503
+    #[derive(Debug)]
+    pub struct DatabaseUnavailable {
+        pub database: &'static str,
+        pub reason: String,
+    }
+
+    impl fmt::Display for DatabaseUnavailable {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{} is unavailable: {}", self.database, self.reason)
+        }
+    }
+
+    impl std::error::Error for DatabaseUnavailable {}
+
+    /// the settings for a data store are wrong, so retrying can't help;
+    /// surfaced to clients as a plain 500 rather than a 503
+    #[derive(Debug)]
+    pub struct Misconfigured {
+        pub database: &'static str,
+        pub reason: String,
+    }
+
+    impl fmt::Display for Misconfigured {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{} is misconfigured: {}", self.database, self.reason)
+        }
+    }
+
+    impl std::error::Error for Misconfigured {}
+
+    pub fn misconfigured(database: &'static str, reason: impl fmt::Display) -> anyhow::Error {
+        Misconfigured {
+            database,
+            reason: reason.to_string(),
+        }
+        .into()
+    }
+
+    /// retries `connect` with exponential backoff; nothing is cached on failure,
+    /// so the next caller tries again instead of the process exiting. fails with
+    /// `DatabaseUnavailable` once attempts run out, or straight away with
+    /// `Misconfigured` when `connect` reports one
+    pub async fn connect_with_backoff<T, F, Fut>(
+        database: &'static str,
+        mut connect: F,
+    ) -> Result<T>
+    where
+        F: FnMut() -> Fut + Send,
+        Fut: Future<Output = Result<T>> + Send,
+    {
+        let mut delay = CONNECT_BACKOFF;
+        let mut attempt = 1;
+        loop {
+            let connected = tokio::time::timeout(CONNECT_ATTEMPT_TIMEOUT, connect())
+                .await
+                .unwrap_or_else(|_| Err(anyhow!("timed out after {CONNECT_ATTEMPT_TIMEOUT:?}")));
+            match connected {
+                Ok(connected) => return Ok(connected),
+                Err(err) if err.is::<Misconfigured>() => {
+                    tracing::error!("connecting to {database}: {err}");
+                    return Err(err);
+                }
+                Err(err) if attempt < CONNECT_MAX_ATTEMPTS => {
+                    tracing::warn!("connecting to {database} (attempt {attempt}): {err}");
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                    attempt += 1;
+                }
+                Err(err) => {
+                    tracing::error!("connecting to {database}: {err}");
+                    return Err(DatabaseUnavailable {
+                        database,
+                        reason: err.to_string(),
+                    }
+                    .into());
+                }
+            }
+        }
+    }
+
+    async fn connect() -> Result<Client> {
+        let env = Env::global().map_err(|err| misconfigured("mongodb", err))?;
+        let mut client_options = ClientOptions::parse(&env.mongo_uri)
+            .await
+            .map_err(|err| match *err.kind {
+                ErrorKind::InvalidArgument { .. } => misconfigured("mongodb", err),
+                _ => err.into(),
+            })?;
+        client_options.max_pool_size = Some(env.mongo_pool_size);
+        client_options.connect_timeout = Some(CONNECT_TIMEOUT);
+        client_options.server_selection_timeout = Some(CONNECT_TIMEOUT);
+        let client = Client::with_options(client_options)?;
+        let database = client.default_database().ok_or_else(|| {
+            misconfigured("mongodb", "no default database found in MONGO_URI")
+        })?;
+        // the driver connects lazily, so ping to find out now whether the server is reachable
+        database.run_command(doc! { "ping": 1 }, None).await?;
+        Ok(client)
+    }
+
+    pub async fn client() -> Result<&'static Client> {
+        CLIENT
+            .get_or_try_init(|| connect_with_backoff("mongodb", connect))
+            .await
+    }
+
+    /// round trip to the server, used by readiness checks
+    pub async fn ping() -> Result<()> {
+        database().await?.run_command(doc! { "ping": 1 }, None).await?;
+        Ok(())
+    }
+
+    pub async fn database() -> Result<Database> {
+        let client = client().await?;
+        client
+            .default_database()
+            .ok_or_else(|| misconfigured("mongodb", "no default database found"))
+    }
+
+    const TRANSACTION_MAX_ATTEMPTS: u32 = 5;
+
+    fn has_label(err: &anyhow::Error, label: &str) -> bool {
+        matches!(err.downcast_ref::<MongoError>(), Some(err) if err.contains_label(label))
+    }
+
+    /// runs `callback` inside a transaction, retrying the whole callback on transient
+    /// transaction errors and the commit on unknown commit results.
+    /// transactions require mongo to be running as a replica set
+    pub async fn with_transaction<T, F>(mut callback: F) -> Result<T>
+    where
+        T: Send,
+        F: for<'a> FnMut(&'a mut ClientSession) -> BoxFuture<'a, Result<T>> + Send,
+    {
+        let mut session = client().await?.start_session(None).await?;
+        let mut attempt = 0;
+        'transaction: loop {
+            attempt += 1;
+            session.start_transaction(None).await?;
+            let value = match callback(&mut session).await {
+                Ok(value) => value,
+                Err(err) => {
+                    if let Err(abort) = session.abort_transaction().await {
+                        tracing::warn!("error aborting transaction: {abort:?}");
+                    }
+                    if has_label(&err, TRANSIENT_TRANSACTION_ERROR)
+                        && attempt < TRANSACTION_MAX_ATTEMPTS
+                    {
+                        continue 'transaction;
+                    }
+                    return Err(err);
+                }
+            };
+            loop {
+                match session.commit_transaction().await {
+                    Ok(()) => return Ok(value),
+                    Err(err)
+                        if err.contains_label(UNKNOWN_TRANSACTION_COMMIT_RESULT)
+                            && attempt < TRANSACTION_MAX_ATTEMPTS =>
+                    {
+                        attempt += 1;
+                    }
+                    Err(err)
+                        if err.contains_label(TRANSIENT_TRANSACTION_ERROR)
+                            && attempt < TRANSACTION_MAX_ATTEMPTS =>
+                    {
+                        continue 'transaction;
+                    }
+                    Err(err) => return Err(err.into()),
+                }
+            }
+        }
+    }
+
+    pub fn generate_nanoid() -> String {
+        // ~2 million years needed, in order to have a 1% probability of at least one collision.
+        // https://zelark.github.io/nano-id-cc/
+        let alphabet = [
+            'A', 'B', 'C', 'D', 'E', 'F', 'G', 'H', 'I', 'J', 'K', 'L', 'M', 'N', 'O', 'P', 'Q',
+            'R', 'S', 'T', 'U', 'V', 'W', 'X', 'Y', 'Z',
+        ];
+        nanoid!(20, &alphabet)
+    }
+
+    #[derive(Serialize, Default)]
+    pub struct ListQueryOptions {
+        pub limit: Option<i64>,
+        pub skip: Option<u64>,
+        pub sort: Option<Document>,
+        pub projection: Option<Document>,
+        /// includes soft deleted documents
+        pub with_deleted: bool,
+    }
+
+    #[derive(Serialize, Default)]
+    pub struct FindQueryOptions {
+        pub projection: Option<Document>,
+        /// includes soft deleted documents
+        pub with_deleted: bool,
+    }
+
+    impl From<ListQueryOptions> for FindOptions {
+        fn from(opts: ListQueryOptions) -> Self {
+            Self::builder()
+                .skip(opts.skip)
+                .limit(opts.limit)
+                .sort(opts.sort)
+                .projection(opts.projection)
+                .build()
+        }
+    }
+
+    impl From<FindQueryOptions> for FindOneOptions {
+        fn from(opts: FindQueryOptions) -> Self {
+            Self::builder().projection(opts.projection).build()
+        }
+    }
+
+    #[derive(Serialize, Default)]
+    pub struct PageQueryOptions {
+        pub limit: Option<i64>,
+        pub cursor: Option<String>,
+        pub sort: Option<Document>,
+        /// includes soft deleted documents
+        pub with_deleted: bool,
+    }
+
+    #[derive(Debug, Serialize)]
+    pub struct Page<T> {
+        pub items: Vec<T>,
+        pub next_cursor: Option<String>,
+        pub has_more: bool,
+    }
+
+    /// one page of an offset paginated listing, with the total across every page
+    #[derive(Debug, Serialize)]
+    pub struct Paginated<T> {
+        pub items: Vec<T>,
+        pub total: u64,
+        pub page: u64,
+        pub pages: u64,
+    }
+
+    impl<T> Paginated<T> {
+        pub fn new(items: Vec<T>, total: u64, page: u64, limit: u64) -> Self {
+            let pages = match total {
+                0 => 0,
+                total => (total - 1) / limit.max(1) + 1,
+            };
+            Self {
+                items,
+                total,
+                page,
+                pages,
+            }
+        }
+
+        /// the page `list` fetches, with the `count` across every page; both run at once
+        pub async fn fetch<L, C>(request: PageRequest, list: L, count: C) -> Result<Self>
+        where
+            L: Future<Output = Result<Vec<T>>>,
+            C: Future<Output = Result<u64>>,
+        {
+            let (items, total) = futures::try_join!(list, count)?;
+            Ok(Self::new(items, total, request.page, request.limit))
+        }
+
+        pub fn map<U>(self, f: impl FnOnce(Vec<T>) -> Vec<U>) -> Paginated<U> {
+            Paginated {
+                items: f(self.items),
+                total: self.total,
+                page: self.page,
+                pages: self.pages,
+            }
+        }
+    }
+
+    impl<T: Serialize> Paginated<T> {
+        /// the page as json, with a `Link` header to its neighbouring pages
+        pub fn respond(&self, req: &HttpRequest) -> HttpResponse {
+            let mut response = HttpResponse::Ok();
+            if let Some(links) = pagination_links(req, self.page, self.pages) {
+                response.insert_header((header::LINK, links));
+            }
+            response.json(self)
+        }
+    }
+
+    /// the most items a page can hold, unless a listing sets a lower cap of its own
+    pub const PAGINATION_MAX: u64 = 100;
+
+    /// the `page` and `limit` asked for, defaulting to the first page of 20
+    #[derive(Debug, Clone, Copy)]
+    pub struct PageRequest {
+        pub page: u64,
+        pub limit: u64,
+    }
+
+    impl PageRequest {
+        /// holds `limit` between 1 and `max`, whatever is asked for
+        pub fn new(page: Option<u64>, limit: Option<u64>, max: u64) -> Self {
+            Self {
+                page: page.unwrap_or(1).max(1),
+                limit: limit.unwrap_or(20).clamp(1, max),
+            }
+        }
+
+        /// options listing this page in `sort` order
+        pub fn options(self, sort: Document) -> Result<ListQueryOptions> {
+            Ok(ListQueryOptions {
+                limit: Some(i64::try_from(self.limit)?),
+                skip: Some((self.page - 1).saturating_mul(self.limit)),
+                sort: Some(sort),
+                ..Default::default()
+            })
+        }
+    }
+
+    #[derive(Debug)]
+    pub struct InvalidCursor;
+
+    impl fmt::Display for InvalidCursor {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "cursor is invalid or does not match the requested sort")
+        }
+    }
+
+    impl std::error::Error for InvalidCursor {}
+
+    fn cursor_mac(secret: &str, payload: &[u8]) -> Result<HmacSha256> {
+        let mut mac = HmacSha256::new_from_slice(secret.as_bytes())
+            .map_err(|_| anyhow!("invalid cursor secret"))?;
+        mac.update(payload);
+        Ok(mac)
+    }
+
+    /// signs the sort spec and the sort key values of the last item, so a cursor
+    /// cannot be forged or replayed against a different ordering
+    fn encode_cursor(secret: &str, sort: &Document, values: Document) -> Result<String> {
+        let mut payload = vec![];
+        doc! { "sort": sort.clone(), "values": values }.to_writer(&mut payload)?;
+        let signature = cursor_mac(secret, &payload)?.finalize().into_bytes();
+        Ok(format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(&payload),
+            URL_SAFE_NO_PAD.encode(signature)
+        ))
+    }
+
+    fn decode_cursor(secret: &str, cursor: &str, sort: &Document) -> Result<Document> {
+        let (payload, signature) = cursor.split_once('.').ok_or(InvalidCursor)?;
+        let payload = URL_SAFE_NO_PAD
+            .decode(payload)
+            .map_err(|_| InvalidCursor)?;
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| InvalidCursor)?;
+        cursor_mac(secret, &payload)?
+            .verify_slice(&signature)
+            .map_err(|_| InvalidCursor)?;
+        let decoded = Document::from_reader(payload.as_slice()).map_err(|_| InvalidCursor)?;
+        if decoded.get_document("sort").ok() != Some(sort) {
+            return Err(InvalidCursor.into());
+        }
+        let values = decoded
+            .get_document("values")
+            .map_err(|_| InvalidCursor)?;
+        Ok(values.clone())
+    }
+
+    /// sort fields with their direction, always ending on `_id` so the ordering is total
+    fn sort_keys(sort: &Document) -> Vec<(String, i32)> {
+        let mut keys = sort
+            .iter()
+            .map(|(key, direction)| {
+                let descending = match direction {
+                    Bson::Int32(direction) => *direction < 0,
+                    Bson::Int64(direction) => *direction < 0,
+                    Bson::Double(direction) => *direction < 0.0,
+                    _ => false,
+                };
+                (key.clone(), if descending { -1 } else { 1 })
+            })
+            .collect::<Vec<_>>();
+        if !keys.iter().any(|(key, _)| key == "_id") {
+            keys.push(("_id".to_string(), 1));
+        }
+        keys
+    }
+
+    fn lookup_path(document: &Document, path: &str) -> Bson {
+        let mut current = document;
+        let mut segments = path.split('.').peekable();
+        while let Some(segment) = segments.next() {
+            match (current.get(segment), segments.peek()) {
+                (Some(Bson::Document(nested)), Some(_)) => current = nested,
+                (Some(value), None) => return value.clone(),
+                _ => break,
+            }
+        }
+        Bson::Null
+    }
+
+    /// matches every document that sorts strictly after `values`. mongo only compares
+    /// values of the same type, so a missing or null key gets its own clause: nulls sort
+    /// first ascending and last descending
+    fn keyset_filter(keys: &[(String, i32)], values: &Document) -> Document {
+        let value_of = |key: &str| values.get(key).cloned().unwrap_or(Bson::Null);
+        let clauses = (0..keys.len())
+            .filter_map(|position| {
+                let mut clause = Document::new();
+                for (key, _) in &keys[..position] {
+                    clause.insert(key, value_of(key));
+                }
+                let (key, direction) = &keys[position];
+                match (*direction < 0, value_of(key)) {
+                    // nothing comes after a null in descending order
+                    (true, Bson::Null) => return None,
+                    (false, Bson::Null) => clause.insert(key, doc! { "$ne": null }),
+                    (false, value) => clause.insert(key, doc! { "$gt": value }),
+                    (true, value) => clause.insert(
+                        "$or",
+                        vec![doc! { key: { "$lt": value } }, doc! { key: null }],
+                    ),
+                };
+                Some(clause)
+            })
+            .collect::<Vec<_>>();
+        doc! { "$or": clauses }
+    }
+
+    /// how `sync_indexes` changes a collection's indexes, by index name
+    #[derive(Debug, Default, Serialize)]
+    pub struct IndexPlan {
+        pub collection: String,
+        pub create: Vec<String>,
+        /// declared with the same keys but different options, dropped and created again
+        pub recreate: Vec<String>,
+        /// no longer declared
+        pub drop: Vec<String>,
+        pub unchanged: Vec<String>,
+        /// false for a dry run
+        pub applied: bool,
+    }
+
+    const NAMESPACE_NOT_FOUND: i32 = 26;
+
+    /// what makes two indexes the same: their keys and the options we declare
+    #[derive(Debug, PartialEq)]
+    struct IndexShape {
+        keys: Vec<(String, Bson)>,
+        /// fields of a text index, which mongo reports as `_fts`/`_ftsx` keys plus weights
+        text: Vec<String>,
+        unique: bool,
+        sparse: bool,
+        expire_after: Option<u64>,
+        partial_filter: Option<Document>,
+    }
+
+    impl IndexShape {
+        fn of(index: &IndexModel) -> Self {
+            let options = index.options.clone().unwrap_or_default();
+            let mut keys = vec![];
+            let mut text = vec![];
+            for (key, value) in &index.keys {
+                match (key.as_str(), value) {
+                    ("_fts", _) => text.extend(options.weights.iter().flat_map(Document::keys)),
+                    ("_ftsx", _) => {}
+                    (_, Bson::String(kind)) if kind == "text" => text.push(key),
+                    // the server may report directions as doubles or longs
+                    (_, Bson::Int32(_) | Bson::Int64(_) | Bson::Double(_)) => {
+                        let descending = match value {
+                            Bson::Int32(n) => *n < 0,
+                            Bson::Int64(n) => *n < 0,
+                            _ => matches!(value.as_f64(), Some(n) if n < 0.0),
+                        };
+                        keys.push((key.clone(), Bson::Int32(if descending { -1 } else { 1 })));
+                    }
+                    _ => keys.push((key.clone(), value.clone())),
+                }
+            }
+            let mut text = text.into_iter().cloned().collect::<Vec<_>>();
+            text.sort();
+            Self {
+                keys,
+                text,
+                unique: options.unique.unwrap_or(false),
+                sparse: options.sparse.unwrap_or(false),
+                expire_after: options.expire_after.map(|expiry| expiry.as_secs()),
+                partial_filter: options.partial_filter_expression,
+            }
+        }
+
+        fn same_keys(&self, other: &Self) -> bool {
+            self.keys == other.keys && self.text == other.text
+        }
+    }
+
+    /// the index's name, or the one mongo generates from its keys, e.g. `timestamp_-1`
+    fn index_name(index: &IndexModel) -> String {
+        if let Some(name) = index.options.as_ref().and_then(|options| options.name.clone()) {
+            return name;
+        }
+        index
+            .keys
+            .iter()
+            .map(|(key, value)| match value {
+                Bson::String(kind) => format!("{key}_{kind}"),
+                value => format!("{key}_{value}"),
+            })
+            .collect::<Vec<_>>()
+            .join("_")
+    }
+
+    /// `index` with every direction reversed and a temporary name, to stand in for the index
+    /// it replaces while that is swapped, since mongo won't hold two indexes on the same keys.
+    /// `None` for text and other keys without a direction
+    fn stand_in(index: &IndexModel) -> Option<IndexModel> {
+        let mut keys = Document::new();
+        for (key, value) in &index.keys {
+            let reversed = match value {
+                Bson::Int32(order) => Bson::Int32(-order),
+                Bson::Int64(order) => Bson::Int64(-order),
+                Bson::Double(order) => Bson::Double(-order),
+                _ => return None,
+            };
+            keys.insert(key, reversed);
+        }
+        let mut options = index.options.clone().unwrap_or_default();
+        options.name = Some(format!("{}_sync", index_name(index)));
+        Some(IndexModel::builder().keys(keys).options(options).build())
+    }
+
+    async fn existing_indexes<T>(collection: &Collection<T>) -> Result<Vec<IndexModel>> {
+        let indexes = match collection.list_indexes(None).await {
+            Ok(cursor) => cursor.try_collect::<Vec<_>>().await?,
+            // nothing has been written to the collection yet
+            Err(err)
+                if matches!(
+                    &*err.kind,
+                    ErrorKind::Command(command) if command.code == NAMESPACE_NOT_FOUND
+                ) =>
+            {
+                vec![]
+            }
+            Err(err) => return Err(err.into()),
+        };
+        Ok(indexes
+            .into_iter()
+            .filter(|index| index_name(index) != "_id_")
+            .collect())
+    }
+
+    /// marks a soft deleted document with the time it was deleted
+    pub const DELETED_AT: &str = "deleted_at";
+
+    /// narrows `filter` to documents that aren't soft deleted, unless it already
+    /// constrains `deleted_at` itself
+    fn exclude_deleted(filter: Option<Document>) -> Document {
+        let mut filter = filter.unwrap_or_default();
+        if !filter.contains_key(DELETED_AT) {
+            filter.insert(DELETED_AT, Bson::Null);
+        }
+        filter
+    }
+
+    /// a reference to a document of another model, stored as its id and swapped for the
+    /// document itself by `populate`
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    #[serde(untagged)]
+    pub enum Ref<T> {
+        Id(String),
+        Document(T),
+    }
+
+    impl<T: Model> Ref<T> {
+        /// the referenced id, whether or not the document has been populated
+        pub fn id(&self) -> Bson {
+            match self {
+                Self::Id(id) => Bson::String(id.clone()),
+                Self::Document(document) => document.document_id(),
+            }
+        }
+
+        pub fn refers_to(&self, id: &str) -> bool {
+            matches!(self.id(), Bson::String(referenced) if referenced == id)
+        }
+
+        /// the document, when populated
+        pub const fn document(&self) -> Option<&T> {
+            match self {
+                Self::Id(_) => None,
+                Self::Document(document) => Some(document),
+            }
+        }
+    }
+
+    impl<T> From<String> for Ref<T> {
+        fn from(id: String) -> Self {
+            Self::Id(id)
+        }
+    }
+
+    impl<T: Model> From<Ref<T>> for Bson {
+        fn from(reference: Ref<T>) -> Self {
+            reference.id()
+        }
+    }
+
+    /// a field of a model holding `Ref`s to another model, declared by `#[derive(Model)]`
+    #[derive(Debug, Clone, Copy)]
+    pub struct Relation {
+        pub field: &'static str,
+        pub collection: &'static str,
+        /// the field holds one `Ref` rather than an array of them
+        pub single: bool,
+        scoped: fn(Option<Document>, bool) -> Option<Document>,
+        relations: fn() -> Vec<Relation>,
+    }
+
+    impl Relation {
+        /// a `Vec<Ref<T>>` field
+        pub fn many<T: Model>(field: &'static str) -> Self {
+            Self {
+                field,
+                collection: T::collection_name(),
+                single: false,
+                scoped: T::scoped,
+                relations: T::relations,
+            }
+        }
+
+        /// a `Ref<T>` or `Option<Ref<T>>` field
+        pub fn one<T: Model>(field: &'static str) -> Self {
+            Self {
+                single: true,
+                ..Self::many::<T>(field)
+            }
+        }
+    }
+
+    /// how `read_populate` and `list_populate` join `local_field` to the documents of `from`
+    /// whose `foreign_field` matches. like `populate`, each id is replaced by its document in
+    /// place and ids with no match are left as they are.
+    ///
+    /// a `filter` or `projection` runs a pipeline alongside `localField` and `foreignField`,
+    /// which needs mongodb 5.0 or later; a projection has to keep `foreign_field`
+    #[derive(Debug, Clone)]
+    pub struct Populate {
+        pub local_field: String,
+        pub from: String,
+        pub foreign_field: String,
+        /// `local_field` holds one id rather than an array of them
+        pub single: bool,
+        pub projection: Option<Document>,
+        /// only joins foreign documents matching this, e.g. ones that aren't soft deleted
+        pub filter: Option<Document>,
+    }
+
+    impl Populate {
+        /// joins an array of ids in `local_field` to `_id` in `from`
+        pub fn new(local_field: &str, from: &str) -> Self {
+            Self {
+                local_field: local_field.to_string(),
+                from: from.to_string(),
+                foreign_field: "_id".to_string(),
+                single: false,
+                projection: None,
+                filter: None,
+            }
+        }
+
+        /// joins the `field` relation `M` declares, leaving out soft deleted documents
+        pub fn of<M: Model>(field: &str) -> Result<Self> {
+            let relation = M::relations()
+                .into_iter()
+                .find(|relation| relation.field == field)
+                .ok_or_else(|| anyhow!("{field} is not a reference to another model"))?;
+            Ok(Self {
+                single: relation.single,
+                filter: (relation.scoped)(None, false),
+                ..Self::new(field, relation.collection)
+            })
+        }
+
+        #[must_use]
+        pub fn foreign_field(mut self, field: &str) -> Self {
+            self.foreign_field = field.to_string();
+            self
+        }
+
+        #[must_use]
+        pub const fn single(mut self) -> Self {
+            self.single = true;
+            self
+        }
+
+        #[must_use]
+        pub fn project(mut self, projection: Document) -> Self {
+            self.projection = Some(projection);
+            self
+        }
+
+        /// the aggregation stages performing the join
+        fn stages(&self) -> Vec<Document> {
+            let field = self.local_field.as_str();
+            // joined into a scratch field first, so the ids can be swapped in place
+            let joined = format!("{field}_populated");
+            let mut lookup = doc! {
+                "from": &self.from,
+                "localField": field,
+                "foreignField": &self.foreign_field,
+                "as": &joined,
+            };
+            let mut pipeline = vec![];
+            if let Some(filter) = &self.filter {
+                pipeline.push(doc! { "$match": filter.clone() });
+            }
+            if let Some(projection) = &self.projection {
+                pipeline.push(doc! { "$project": projection.clone() });
+            }
+            if !pipeline.is_empty() {
+                lookup.insert("pipeline", pipeline);
+            }
+            let local = format!("${field}");
+            let found = |id: &str| {
+                doc! {
+                    "$arrayElemAt": [{
+                        "$filter": {
+                            "input": format!("${joined}"),
+                            "cond": { "$eq": [format!("$$this.{}", self.foreign_field), id] },
+                        }
+                    }, 0]
+                }
+            };
+            let populated = if self.single {
+                doc! { "$ifNull": [found(&local), &local] }
+            } else {
+                doc! {
+                    "$map": {
+                        "input": { "$ifNull": [&local, []] },
+                        "as": "id",
+                        "in": { "$ifNull": [found("$$id"), "$$id"] },
+                    }
+                }
+            };
+            vec![
+                doc! { "$lookup": lookup },
+                doc! { "$addFields": { field: populated } },
+                doc! { "$project": { joined: 0 } },
+            ]
+        }
+    }
+
+    /// ids at `value` that haven't been populated yet
+    fn referenced_ids(value: Option<&Bson>) -> Vec<Bson> {
+        match value {
+            Some(Bson::Array(items)) => items
+                .iter()
+                .filter(|item| !matches!(item, Bson::Document(_) | Bson::Null))
+                .cloned()
+                .collect(),
+            Some(Bson::Document(_) | Bson::Null) | None => vec![],
+            Some(id) => vec![id.clone()],
+        }
+    }
+
+    /// swaps the ids at `value` for the documents in `found`, leaving ids with no match
+    fn resolve(value: &mut Bson, found: &HashMap<String, Document>) {
+        match value {
+            Bson::Array(items) => {
+                for item in items {
+                    resolve(item, found);
+                }
+            }
+            Bson::Document(_) | Bson::Null => {}
+            id => {
+                if let Some(document) = found.get(&id.to_string()) {
+                    *id = Bson::Document(document.clone());
+                }
+            }
+        }
+    }
+
+    /// populates `paths` of `documents`, which have `relations`; nested paths such as
+    /// `todos.assignee` populate `todos` first and then the `assignee` of each todo
+    fn populate_documents<'a>(
+        documents: &'a mut [Document],
+        relations: &'a [Relation],
+        paths: &'a [&'a str],
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let mut groups: Vec<(&str, Vec<&str>)> = vec![];
+            for path in paths {
+                let (field, rest) = match path.split_once('.') {
+                    Some((field, rest)) => (field, Some(rest)),
+                    None => (*path, None),
+                };
+                match groups.iter_mut().find(|(group, _)| *group == field) {
+                    Some((_, nested)) => nested.extend(rest),
+                    None => groups.push((field, rest.into_iter().collect())),
+                }
+            }
+            for (field, nested) in groups {
+                let relation = relations
+                    .iter()
+                    .find(|relation| relation.field == field)
+                    .ok_or_else(|| anyhow!("{field} is not a reference to another model"))?;
+                let ids = documents
+                    .iter()
+                    .flat_map(|document| referenced_ids(document.get(field)))
+                    .collect::<Vec<_>>();
+                if ids.is_empty() {
+                    continue;
+                }
+                // one query per path however many documents refer to it
+                let filter = (relation.scoped)(Some(doc! { "_id": { "$in": ids } }), false);
+                let mut found = database()
+                    .await?
+                    .collection::<Document>(relation.collection)
+                    .find(filter, None)
+                    .await?
+                    .try_collect::<Vec<_>>()
+                    .await?;
+                if !nested.is_empty() {
+                    let nested_relations = (relation.relations)();
+                    populate_documents(&mut found, &nested_relations, &nested).await?;
+                }
+                let found = found
+                    .into_iter()
+                    .filter_map(|document| Some((document.get("_id")?.to_string(), document)))
+                    .collect::<HashMap<_, _>>();
+                for document in documents.iter_mut() {
+                    if let Some(value) = document.get_mut(field) {
+                        resolve(value, &found);
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+
+    #[async_trait]
+    pub trait Model: Unpin + Serialize + Sized + Send + Sync + DeserializeOwned {
+        fn collection_name<'a>() -> &'a str;
+
+        /// the document's `_id`
+        fn document_id(&self) -> Bson {
+            bson::to_document(self)
+                .ok()
+                .and_then(|mut document| document.remove("_id"))
+                .unwrap_or(Bson::Null)
+        }
+
+        /// fields holding `Ref`s, declared through `#[derive(Model)]`
+        fn relations() -> Vec<Relation> {
+            vec![]
+        }
+
+        /// indexes the model declares, usually through `#[derive(Model)]`
+        fn indexes() -> Vec<IndexModel> {
+            vec![]
+        }
+
+        /// brings the collection's indexes in line with `indexes`: creates missing ones,
+        /// recreates ones whose options changed and drops ones no longer declared.
+        /// a dry run only reports the plan. indexes are created before any are dropped and
+        /// the first error stops the sync, so an index that can't be built, e.g. a unique one
+        /// blocked by duplicates, leaves the one it would replace in place
+        async fn sync_indexes(dry_run: bool) -> Result<IndexPlan> {
+            let collection = Self::collection().await?;
+            let mut existing = existing_indexes(&collection).await?;
+            let mut plan = IndexPlan {
+                collection: Self::collection_name().to_string(),
+                applied: !dry_run,
+                ..Default::default()
+            };
+            let mut missing = vec![];
+            let mut changed = vec![];
+            for index in Self::indexes() {
+                let shape = IndexShape::of(&index);
+                let found = existing
+                    .iter()
+                    .position(|existing| IndexShape::of(existing).same_keys(&shape));
+                let Some(position) = found else {
+                    plan.create.push(index_name(&index));
+                    missing.push(index);
+                    continue;
+                };
+                let current = existing.remove(position);
+                if IndexShape::of(&current) == shape {
+                    plan.unchanged.push(index_name(&current));
+                } else {
+                    plan.recreate.push(index_name(&index));
+                    changed.push((current, index));
+                }
+            }
+            plan.drop = existing.iter().map(index_name).collect();
+            if dry_run {
+                return Ok(plan);
+            }
+            for index in missing {
+                collection.create_index(index, None).await?;
+            }
+            for (current, index) in changed {
+                let Some(stand_in) = stand_in(&index) else {
+                    // a collection holds one text index, so it can only be swapped in place
+                    collection.drop_index(index_name(&current), None).await?;
+                    if let Err(err) = collection.create_index(index, None).await {
+                        collection.create_index(current, None).await?;
+                        return Err(err.into());
+                    }
+                    continue;
+                };
+                let stand_in_name = index_name(&stand_in);
+                collection.create_index(stand_in, None).await?;
+                collection.drop_index(index_name(&current), None).await?;
+                collection.create_index(index, None).await?;
+                collection.drop_index(stand_in_name, None).await?;
+            }
+            for name in &plan.drop {
+                collection.drop_index(name, None).await?;
+            }
+            Ok(plan)
+        }
+
+        /// opts the model into soft delete: `soft_delete` marks documents with `deleted_at`
+        /// instead of removing them, and reads leave them out unless asked to include them
+        const SOFT_DELETE: bool = false;
+
+        /// logs every write to the `audit_log` collection; opt out for internal or high
+        /// volume collections
+        const AUDIT: bool = true;
+
+        /// `filter` with soft deleted documents left out, when the model opts in
+        fn scoped(filter: Option<Document>, with_deleted: bool) -> Option<Document> {
+            if Self::SOFT_DELETE && !with_deleted {
+                return Some(exclude_deleted(filter));
+            }
+            filter
+        }
+
+        async fn collection() -> Result<Collection<Self>> {
+            let name = Self::collection_name();
+            Ok(database().await?.collection::<Self>(name))
+        }
+
+        /// snapshots what a write to `filter` is about to change, for the audit log
+        async fn trail(
+            filter: &Document,
+            reach: Reach,
+            session: Option<&mut ClientSession>,
+        ) -> Result<Trail> {
+            if !Self::AUDIT {
+                return Ok(Trail::disabled());
+            }
+            let collection = Self::collection().await?.clone_with_type();
+            Trail::begin(collection, filter, reach, session).await
+        }
+
+        /// the audit trail of a newly inserted document
+        async fn insert_trail(id: Bson) -> Result<Trail> {
+            if !Self::AUDIT {
+                return Ok(Trail::disabled());
+            }
+            let collection = Self::collection().await?.clone_with_type();
+            Ok(Trail::inserted(collection, id))
+        }
+
+        async fn count() -> Result<u64> {
+            // the estimate comes from collection metadata, which can't leave anything out
+            if Self::SOFT_DELETE {
+                return Self::count_documents(None::<Document>).await;
+            }
+            let count = Self::collection()
+                .await?
+                .estimated_document_count(None)
+                .await?;
+            Ok(count)
+        }
+
+        /// exact count of the documents matching `filter`, unlike the estimated `count`;
+        /// soft deleted documents are only counted when `filter` constrains `deleted_at`
+        async fn count_documents<F: IntoFilter<Self>>(filter: Option<F>) -> Result<u64> {
+            let filter = Self::scoped(filter.map(IntoFilter::into_filter), false);
+            let count = Self::collection()
+                .await?
+                .count_documents(filter, None)
+                .await?;
+            Ok(count)
+        }
+
+        async fn save(&self) -> Result<&Self> {
+            let inserted = Self::collection().await?.insert_one(self, None).await?;
+            Self::insert_trail(inserted.inserted_id).await?.settle().await;
+            Ok(self)
+        }
+
+        /// like reads, updates leave soft deleted documents alone unless `filter` constrains
+        /// `deleted_at`
+        async fn update_one<F, U>(filter: F, updates: U) -> Result<UpdateResult>
+        where
+            F: IntoFilter<Self>,
+            U: IntoUpdate<Self>,
+        {
+            let filter = Self::scoped(Some(filter.into_filter()), false).unwrap_or_default();
+            let updates = updates.into_update();
+            let trail = Self::trail(&filter, Reach::One, None).await?;
+            let updated = Self::collection()
+                .await?
+                .update_one(trail.narrow(filter), updates, None)
+                .await?;
+            trail.settle().await;
+            Ok(updated)
+        }
+
+        async fn update_many<F, U>(filter: F, updates: U) -> Result<UpdateResult>
+        where
+            F: IntoFilter<Self>,
+            U: IntoUpdate<Self>,
+        {
+            let filter = Self::scoped(Some(filter.into_filter()), false).unwrap_or_default();
+            let updates = updates.into_update();
+            let trail = Self::trail(&filter, Reach::Many(Operation::Update), None).await?;
+            let updated = Self::collection()
+                .await?
+                .update_many(trail.narrow(filter), updates, None)
+                .await?;
+            trail.settle().await;
+            Ok(updated)
+        }
+
+        async fn upsert(filter: Document, updates: Document) -> Result<UpdateResult> {
+            let mut trail = Self::trail(&filter, Reach::One, None).await?;
+            // with nothing matched the filter has to stay as is for the insert
+            let filter = if trail.is_empty() {
+                filter
+            } else {
+                trail.narrow(filter)
+            };
+            let options = UpdateOptions::builder().upsert(true).build();
+            let updated = Self::collection()
+                .await?
+                .update_one(filter, updates, options)
+                .await?;
+            trail.upserted(updated.upserted_id.clone());
+            trail.settle().await;
+            Ok(updated)
+        }
+
+        /// applies `updates` to the first match and returns the updated document
+        async fn find_one_and_update<F, U>(filter: F, updates: U) -> Result<Option<Self>>
+        where
+            F: IntoFilter<Self>,
+            U: IntoUpdate<Self>,
+        {
+            let filter = Self::scoped(Some(filter.into_filter()), false).unwrap_or_default();
+            let updates = updates.into_update();
+            let trail = Self::trail(&filter, Reach::One, None).await?;
+            let options = FindOneAndUpdateOptions::builder()
+                .return_document(ReturnDocument::After)
+                .build();
+            let updated = Self::collection()
+                .await?
+                .find_one_and_update(trail.narrow(filter), updates, options)
+                .await?;
+            trail.settle().await;
+            Ok(updated)
+        }
+
+        /// removes the first match and returns the removed document
+        async fn find_one_and_delete(filter: Document) -> Result<Option<Self>> {
+            let trail = Self::trail(&filter, Reach::One, None).await?;
+            let deleted = Self::collection()
+                .await?
+                .find_one_and_delete(trail.narrow(filter), None)
+                .await?;
+            trail.settle().await;
+            Ok(deleted)
+        }
+
+        async fn delete_one(filter: Document) -> Result<DeleteResult> {
+            let trail = Self::trail(&filter, Reach::One, None).await?;
+            let deleted = Self::collection()
+                .await?
+                .delete_one(trail.narrow(filter), None)
+                .await?;
+            trail.settle().await;
+            Ok(deleted)
+        }
+
+        async fn delete_many(filter: Document) -> Result<DeleteResult> {
+            let trail = Self::trail(&filter, Reach::Many(Operation::Delete), None).await?;
+            let deleted = Self::collection()
+                .await?
+                .delete_many(trail.narrow(filter), None)
+                .await?;
+            trail.settle().await;
+            Ok(deleted)
+        }
+
+        /// marks the matches as deleted; they stay in the collection until purged
+        async fn soft_delete(filter: Document) -> Result<UpdateResult> {
+            if !Self::SOFT_DELETE {
+                bail!("{} does not support soft delete", Self::collection_name());
+            }
+            let mut filter = filter;
+            filter.insert(DELETED_AT, Bson::Null);
+            Self::update_many(filter, doc! { "$set": { DELETED_AT: chrono::Utc::now() } }).await
+        }
+
+        /// brings back soft deleted matches
+        async fn restore(filter: Document) -> Result<UpdateResult> {
+            if !Self::SOFT_DELETE {
+                bail!("{} does not support soft delete", Self::collection_name());
+            }
+            let mut filter = filter;
+            filter.insert(DELETED_AT, doc! { "$ne": null });
+            Self::update_many(filter, doc! { "$unset": { DELETED_AT: "" } }).await
+        }
+
+        /// ids of the documents soft deleted more than `retention` ago, the ones
+        /// `purge_deleted` removes
+        async fn expired_deletions(retention: chrono::Duration) -> Result<Vec<Bson>> {
+            if !Self::SOFT_DELETE {
+                return Ok(vec![]);
+            }
+            let filter = doc! { DELETED_AT: { "$lt": chrono::Utc::now() - retention } };
+            let options = FindOptions::builder().projection(doc! { "_id": 1 }).build();
+            let ids = Self::collection()
+                .await?
+                .clone_with_type::<Document>()
+                .find(filter, options)
+                .await?
+                .try_filter_map(|document| async move { Ok(document.get("_id").cloned()) })
+                .try_collect::<Vec<_>>()
+                .await?;
+            Ok(ids)
+        }
+
+        /// permanently removes documents soft deleted more than `retention` ago and returns
+        /// their ids. models referenced from others should purge `expired_deletions` in a
+        /// transaction with the writes dropping those references instead
+        async fn purge_deleted(retention: chrono::Duration) -> Result<Vec<Bson>> {
+            let ids = Self::expired_deletions(retention).await?;
+            // small enough batches that each purged document keeps its own audit entry
+            for batch in ids.chunks(MAX_AUDITED_WRITE) {
+                Self::delete_many(doc! { "_id": { "$in": batch } }).await?;
+            }
+            Ok(ids)
+        }
+
+        async fn read<F: IntoFilter<Self>>(
+            filter: Option<F>,
+            options: Option<FindQueryOptions>,
+        ) -> Result<Option<Self>> {
+            let with_deleted =
+                matches!(&options, Some(FindQueryOptions { with_deleted: true, .. }));
+            let filter = Self::scoped(filter.map(IntoFilter::into_filter), with_deleted);
+            let opts = options.map(FindOneOptions::from);
+            let found = Self::collection().await?.find_one(filter, opts).await?;
+            Ok(found)
+        }
+
+        async fn list<F: IntoFilter<Self>>(
+            filter: Option<F>,
+            options: Option<ListQueryOptions>,
+        ) -> Result<Vec<Self>> {
+            let with_deleted =
+                matches!(&options, Some(ListQueryOptions { with_deleted: true, .. }));
+            let filter = Self::scoped(filter.map(IntoFilter::into_filter), with_deleted);
+            let opts = options.map(FindOptions::from);
+            let mut result = Self::collection().await?.find(filter, opts).await?;
+            let mut docs = vec![];
+            while let Some(doc) = result.try_next().await? {
+                docs.push(doc);
+            }
+            Ok(docs)
+        }
+
+        async fn save_with_session(&self, session: &mut ClientSession) -> Result<&Self> {
+            let inserted = Self::collection()
+                .await?
+                .insert_one_with_session(self, None, &mut *session)
+                .await?;
+            let trail = Self::insert_trail(inserted.inserted_id).await?;
+            trail.record(Some(session)).await?;
+            Ok(self)
+        }
+
+        async fn update_one_with_session<F, U>(
+            filter: F,
+            updates: U,
+            session: &mut ClientSession,
+        ) -> Result<UpdateResult>
+        where
+            F: IntoFilter<Self>,
+            U: IntoUpdate<Self>,
+        {
+            let filter = Self::scoped(Some(filter.into_filter()), false).unwrap_or_default();
+            let updates = updates.into_update();
+            let trail = Self::trail(&filter, Reach::One, Some(&mut *session)).await?;
+            let updated = Self::collection()
+                .await?
+                .update_one_with_session(trail.narrow(filter), updates, None, &mut *session)
+                .await?;
+            trail.record(Some(session)).await?;
+            Ok(updated)
+        }
+
+        async fn update_many_with_session<F, U>(
+            filter: F,
+            updates: U,
+            session: &mut ClientSession,
+        ) -> Result<UpdateResult>
+        where
+            F: IntoFilter<Self>,
+            U: IntoUpdate<Self>,
+        {
+            let filter = Self::scoped(Some(filter.into_filter()), false).unwrap_or_default();
+            let updates = updates.into_update();
+            let trail =
+                Self::trail(&filter, Reach::Many(Operation::Update), Some(&mut *session)).await?;
+            let updated = Self::collection()
+                .await?
+                .update_many_with_session(trail.narrow(filter), updates, None, &mut *session)
+                .await?;
+            trail.record(Some(session)).await?;
+            Ok(updated)
+        }
+
+        async fn upsert_with_session(
+            filter: Document,
+            updates: Document,
+            session: &mut ClientSession,
+        ) -> Result<UpdateResult> {
+            let mut trail = Self::trail(&filter, Reach::One, Some(&mut *session)).await?;
+            let filter = if trail.is_empty() {
+                filter
+            } else {
+                trail.narrow(filter)
+            };
+            let options = UpdateOptions::builder().upsert(true).build();
+            let updated = Self::collection()
+                .await?
+                .update_one_with_session(filter, updates, options, &mut *session)
+                .await?;
+            trail.upserted(updated.upserted_id.clone());
+            trail.record(Some(session)).await?;
+            Ok(updated)
+        }
+
+        async fn find_one_and_update_with_session<F, U>(
+            filter: F,
+            updates: U,
+            session: &mut ClientSession,
+        ) -> Result<Option<Self>>
+        where
+            F: IntoFilter<Self>,
+            U: IntoUpdate<Self>,
+        {
+            let filter = Self::scoped(Some(filter.into_filter()), false).unwrap_or_default();
+            let updates = updates.into_update();
+            let trail = Self::trail(&filter, Reach::One, Some(&mut *session)).await?;
+            let options = FindOneAndUpdateOptions::builder()
+                .return_document(ReturnDocument::After)
+                .build();
+            let updated = Self::collection()
+                .await?
+                .find_one_and_update_with_session(
+                    trail.narrow(filter),
+                    updates,
+                    options,
+                    &mut *session,
+                )
+                .await?;
+            trail.record(Some(session)).await?;
+            Ok(updated)
+        }
+
+        async fn find_one_and_delete_with_session(
+            filter: Document,
+            session: &mut ClientSession,
+        ) -> Result<Option<Self>> {
+            let trail = Self::trail(&filter, Reach::One, Some(&mut *session)).await?;
+            let deleted = Self::collection()
+                .await?
+                .find_one_and_delete_with_session(trail.narrow(filter), None, &mut *session)
+                .await?;
+            trail.record(Some(session)).await?;
+            Ok(deleted)
+        }
+
+        async fn delete_one_with_session(
+            filter: Document,
+            session: &mut ClientSession,
+        ) -> Result<DeleteResult> {
+            let trail = Self::trail(&filter, Reach::One, Some(&mut *session)).await?;
+            let deleted = Self::collection()
+                .await?
+                .delete_one_with_session(trail.narrow(filter), None, &mut *session)
+                .await?;
+            trail.record(Some(session)).await?;
+            Ok(deleted)
+        }
+
+        async fn delete_many_with_session(
+            filter: Document,
+            session: &mut ClientSession,
+        ) -> Result<DeleteResult> {
+            let trail =
+                Self::trail(&filter, Reach::Many(Operation::Delete), Some(&mut *session)).await?;
+            let deleted = Self::collection()
+                .await?
+                .delete_many_with_session(trail.narrow(filter), None, &mut *session)
+                .await?;
+            trail.record(Some(session)).await?;
+            Ok(deleted)
+        }
+
+        async fn read_with_session<F: IntoFilter<Self>>(
+            filter: Option<F>,
+            options: Option<FindQueryOptions>,
+            session: &mut ClientSession,
+        ) -> Result<Option<Self>> {
+            let with_deleted =
+                matches!(&options, Some(FindQueryOptions { with_deleted: true, .. }));
+            let filter = Self::scoped(filter.map(IntoFilter::into_filter), with_deleted);
+            let opts = options.map(FindOneOptions::from);
+            let found = Self::collection()
+                .await?
+                .find_one_with_session(filter, opts, session)
+                .await?;
+            Ok(found)
+        }
+
+        async fn list_with_session<F: IntoFilter<Self>>(
+            filter: Option<F>,
+            options: Option<ListQueryOptions>,
+            session: &mut ClientSession,
+        ) -> Result<Vec<Self>> {
+            let with_deleted =
+                matches!(&options, Some(ListQueryOptions { with_deleted: true, .. }));
+            let filter = Self::scoped(filter.map(IntoFilter::into_filter), with_deleted);
+            let opts = options.map(FindOptions::from);
+            let mut result = Self::collection()
+                .await?
+                .find_with_session(filter, opts, &mut *session)
+                .await?;
+            let mut docs = vec![];
+            while let Some(doc) = result.next(&mut *session).await.transpose()? {
+                docs.push(doc);
+            }
+            Ok(docs)
+        }
+
+        /// keyset pagination: each page resumes after the sort key of the previous page's last item
+        async fn list_page(
+            filter: Option<Document>,
+            options: PageQueryOptions,
+        ) -> Result<Page<Self>> {
+            let secret = &Env::global()?.cursor_secret;
+            // bounded here rather than by each caller, which also keeps `limit + 1` in range
+            let limit = options
+                .limit
+                .unwrap_or(20)
+                .clamp(1, i64::try_from(PAGINATION_MAX)?);
+            let keys = sort_keys(&options.sort.unwrap_or_default());
+            let sort = keys
+                .iter()
+                .fold(Document::new(), |mut sort, (key, direction)| {
+                    sort.insert(key, direction);
+                    sort
+                });
+            let mut filters = filter.into_iter().collect::<Vec<_>>();
+            if let Some(cursor) = options.cursor {
+                let values = decode_cursor(secret, &cursor, &sort)?;
+                filters.push(keyset_filter(&keys, &values));
+            }
+            let filter = match filters.len() {
+                0 | 1 => filters.pop(),
+                _ => Some(doc! { "$and": filters }),
+            };
+            let opts = ListQueryOptions {
+                limit: Some(limit + 1),
+                sort: Some(sort.clone()),
+                with_deleted: options.with_deleted,
+                ..Default::default()
+            };
+            let mut items = Self::list(filter, Some(opts)).await?;
+            let has_more = items.len() > limit as usize;
+            items.truncate(limit as usize);
+            let next_cursor = match items.last() {
+                Some(last) if has_more => {
+                    let last = bson::to_document(last)?;
+                    let values = keys
+                        .iter()
+                        .fold(Document::new(), |mut values, (key, _)| {
+                            values.insert(key, lookup_path(&last, key));
+                            values
+                        });
+                    Some(encode_cursor(secret, &sort, values)?)
+                }
+                _ => None,
+            };
+            Ok(Page {
+                items,
+                next_cursor,
+                has_more,
+            })
+        }
+
+        async fn aggregate(pipeline: &[bson::Document]) -> Result<Vec<Self>> {
+            let pipeline = pipeline.to_owned();
+            let mut results = Self::collection().await?.aggregate(pipeline, None).await?;
+            let mut docs = vec![];
+            while let Some(doc) = results.try_next().await? {
+                let document = bson::from_document(doc)?;
+                docs.push(document);
+            }
+            Ok(docs)
+        }
+
+        /// fills in the `Ref`s at `paths` of each item, e.g. `["todos", "todos.assignee"]`,
+        /// with one batched `$in` query per path. references to missing or soft deleted
+        /// documents are left as ids
+        async fn populate_many(items: Vec<Self>, paths: &[&str]) -> Result<Vec<Self>> {
+            let mut documents = items
+                .iter()
+                .map(bson::to_document)
+                .collect::<Result<Vec<_>, _>>()?;
+            populate_documents(&mut documents, &Self::relations(), paths).await?;
+            let populated = documents
+                .into_iter()
+                .map(bson::from_document)
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(populated)
+        }
+
+        async fn populate(self, paths: &[&str]) -> Result<Self> {
+            let mut populated = Self::populate_many(vec![self], paths).await?;
+            populated
+                .pop()
+                .ok_or_else(|| anyhow!("{} was lost while populating", Self::collection_name()))
+        }
+
+        /// the first document matching `filter` with `populate` joined in a single aggregation
+        async fn read_populate<T: DeserializeOwned, F: IntoFilter<Self>>(
+            filter: F,
+            populate: &[Populate],
+            options: Option<FindQueryOptions>,
+        ) -> Result<Option<T>> {
+            let options = options.unwrap_or_default();
+            let filter = Self::scoped(Some(filter.into_filter()), options.with_deleted);
+            let mut pipeline = vec![
+                doc! { "$match": filter.unwrap_or_default() },
+                doc! { "$limit": 1 },
+            ];
+            pipeline.extend(populate.iter().flat_map(Populate::stages));
+            if let Some(projection) = options.projection {
+                pipeline.push(doc! { "$project": projection });
+            }
+            let mut results = Self::collection().await?.aggregate(pipeline, None).await?;
+            match results.try_next().await? {
+                Some(document) => Ok(Some(bson::from_document(document)?)),
+                None => Ok(None),
+            }
+        }
+
+        /// a page of the documents matching `filter` with `populate` joined in a single
+        /// aggregation. the join runs after paging so it only touches the page's documents
+        async fn list_populate<T: DeserializeOwned + Send, F: IntoFilter<Self>>(
+            filter: Option<F>,
+            populate: &[Populate],
+            options: Option<ListQueryOptions>,
+        ) -> Result<Vec<T>> {
+            let options = options.unwrap_or_default();
+            let filter = Self::scoped(filter.map(IntoFilter::into_filter), options.with_deleted);
+            let mut pipeline = vec![doc! { "$match": filter.unwrap_or_default() }];
+            if let Some(sort) = options.sort {
+                pipeline.push(doc! { "$sort": sort });
+            }
+            if let Some(skip) = options.skip {
+                pipeline.push(doc! { "$skip": i64::try_from(skip)? });
+            }
+            // like find, a limit of 0 means none and a negative one is its absolute value
+            if let Some(limit) = options.limit.map(i64::abs).filter(|limit| *limit > 0) {
+                pipeline.push(doc! { "$limit": limit });
+            }
+            pipeline.extend(populate.iter().flat_map(Populate::stages));
+            if let Some(projection) = options.projection {
+                pipeline.push(doc! { "$project": projection });
+            }
+            let mut results = Self::collection().await?.aggregate(pipeline, None).await?;
+            let mut items = vec![];
+            while let Some(document) = results.try_next().await? {
+                items.push(bson::from_document(document)?);
+            }
+            Ok(items)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use actix_web::{http::StatusCode, ResponseError};
+
+        use super::*;
+        use crate::errors::ApiError;
+
+        const SECRET: &str = "cursor-secret";
+
+        fn sort() -> Document {
+            doc! { "created_at": -1, "task": 1, "_id": 1 }
+        }
+
+        fn values() -> Document {
+            doc! { "created_at": 20, "task": "milk", "_id": "a" }
+        }
+
+        #[test]
+        fn cursor_round_trips() {
+            let cursor = encode_cursor(SECRET, &sort(), values()).unwrap();
+            assert_eq!(decode_cursor(SECRET, &cursor, &sort()).unwrap(), values());
+        }
+
+        #[test]
+        fn cursor_rejects_tampering() {
+            let cursor = encode_cursor(SECRET, &sort(), values()).unwrap();
+            let (payload, signature) = cursor.split_once('.').unwrap();
+            let mut forged = URL_SAFE_NO_PAD.decode(payload).unwrap();
+            let last = forged.len() - 2;
+            forged[last] ^= 1;
+            let forged = format!("{}.{signature}", URL_SAFE_NO_PAD.encode(forged));
+            for cursor in [forged.as_str(), "", "not-a-cursor", &cursor[..cursor.len() - 1]] {
+                let err = decode_cursor(SECRET, cursor, &sort()).unwrap_err();
+                assert!(err.is::<InvalidCursor>(), "{cursor}");
+            }
+            assert!(decode_cursor("another-secret", &cursor, &sort()).is_err());
+        }
+
+        #[test]
+        fn cursor_only_fits_the_sort_it_was_issued_for() {
+            let cursor = encode_cursor(SECRET, &sort(), values()).unwrap();
+            let reversed = doc! { "created_at": 1, "task": 1, "_id": 1 };
+            let err = decode_cursor(SECRET, &cursor, &reversed).unwrap_err();
+            assert!(err.is::<InvalidCursor>());
+        }
+
+        #[test]
+        fn sort_keys_normalize_directions_and_end_on_id() {
+            let keys = sort_keys(&doc! { "created_at": -1_i64, "task": 1.0, "complete": "x" });
+            let expected = [("created_at", -1), ("task", 1), ("complete", 1), ("_id", 1)];
+            let expected = expected.map(|(key, direction)| (key.to_string(), direction));
+            assert_eq!(keys, expected);
+            assert_eq!(sort_keys(&doc! { "_id": -1 }), vec![("_id".to_string(), -1)]);
+        }
+
+        #[test]
+        fn keyset_filter_follows_mixed_directions() {
+            let keys = sort_keys(&sort());
+            let filter = keyset_filter(&keys, &values());
+            let expected = doc! {
+                "$or": [
+                    { "$or": [{ "created_at": { "$lt": 20 } }, { "created_at": null }] },
+                    { "created_at": 20, "task": { "$gt": "milk" } },
+                    { "created_at": 20, "task": "milk", "_id": { "$gt": "a" } },
+                ]
+            };
+            assert_eq!(filter, expected);
+        }
+
+        #[test]
+        fn keyset_filter_moves_past_null_keys() {
+            let keys = sort_keys(&doc! { "due": 1, "created_at": -1 });
+            let filter = keyset_filter(&keys, &doc! { "due": null, "_id": "a" });
+            let expected = doc! {
+                "$or": [
+                    { "due": { "$ne": null } },
+                    { "due": null, "created_at": null, "_id": { "$gt": "a" } },
+                ]
+            };
+            assert_eq!(filter, expected);
+        }
+
+        #[tokio::test]
+        async fn misconfiguration_fails_without_retrying() {
+            let mut attempts = 0;
+            let err = connect_with_backoff("mongodb", || {
+                attempts += 1;
+                async { Err::<(), _>(misconfigured("mongodb", "no default database")) }
+            })
+            .await
+            .unwrap_err();
+            assert_eq!(attempts, 1);
+            assert!(err.is::<Misconfigured>());
+            assert_eq!(ApiError::from(err).status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+        }
+
+        #[tokio::test]
+        async fn unreachable_store_is_unavailable() {
+            let mut attempts = 0;
+            let err = connect_with_backoff("mongodb", || {
+                attempts += 1;
+                async { Err::<(), _>(anyhow!("connection refused")) }
+            })
+            .await
+            .unwrap_err();
+            assert_eq!(attempts, CONNECT_MAX_ATTEMPTS);
+            assert!(err.is::<DatabaseUnavailable>());
+            assert_eq!(ApiError::from(err).status_code(), StatusCode::SERVICE_UNAVAILABLE);
+        }
+
+        #[tokio::test]
+        async fn hung_attempts_give_up_inside_the_lambda_timeout() {
+            let started = std::time::Instant::now();
+            let err = connect_with_backoff("mongodb", futures::future::pending::<Result<()>>)
+                .await
+                .unwrap_err();
+            assert!(err.is::<DatabaseUnavailable>());
+            assert!(started.elapsed() < Duration::from_secs(6));
+        }
+    }
+}
+
+pub mod audit {
+    use std::{collections::HashMap, future::Future};
+
+    use anyhow::Result;
+    use bson::{doc, Bson, Document};
+    use chrono::{DateTime, Utc};
+    use futures::stream::TryStreamExt;
+    use mongodb::{options::FindOptions, ClientSession, Collection};
+    use serde::{Deserialize, Serialize};
+
+    use crate::database::{generate_nanoid, Model};
+
+    tokio::task_local! {
+        static ACTOR: Option<String>;
+    }
+
+    /// runs `future` with the writes it makes attributed to `actor`
+    pub async fn with_actor<F: Future>(actor: Option<String>, future: F) -> F::Output {
+        ACTOR.scope(actor, future).await
+    }
+
+    /// who the current request acts as, `None` when anonymous or outside a request
+    pub fn current_actor() -> Option<String> {
+        ACTOR.try_with(Clone::clone).ok().flatten()
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum Operation {
+        Insert,
+        Update,
+        Delete,
+    }
+
+    /// one write to one document
+    #[derive(Debug, Deserialize, Serialize, Model)]
+    // auditing the audit log would never end
+    #[model(collection = "audit_log", audit = false)]
+    pub struct AuditEntry {
+        #[serde(rename = "_id")]
+        pub id: String,
+        #[index(compound = "history")]
+        pub collection: String,
+        #[index(compound = "history")]
+        pub document_id: Bson,
+        pub operation: Operation,
+        pub actor: Option<String>,
+        #[index(compound = "history", order = -1)]
+        #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+        pub timestamp: DateTime<Utc>,
+        /// `{ field: { before, after } }` for each top level field the write changed
+        pub changes: Document,
+        /// set instead of `document_id` and `changes` for a write too large to snapshot
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub bulk: Option<BulkWrite>,
+    }
+
+    /// a write to more than `MAX_AUDITED_WRITE` documents, logged as one entry
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    pub struct BulkWrite {
+        pub filter: Document,
+        /// how many documents matched just before the write
+        pub matched: u64,
+    }
+
+    #[derive(Debug, Serialize)]
+    pub struct Normalized {
+        #[serde(rename = "_id")]
+        pub id: String,
+        pub collection: String,
+        pub document_id: serde_json::Value,
+        pub operation: Operation,
+        pub actor: Option<String>,
+        pub timestamp: String,
+        pub changes: serde_json::Value,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub bulk: Option<serde_json::Value>,
+    }
+
+    impl AuditEntry {
+        pub fn normalize(&self) -> Normalized {
+            Normalized {
+                id: self.id.clone(),
+                collection: self.collection.clone(),
+                document_id: self.document_id.clone().into_relaxed_extjson(),
+                operation: self.operation,
+                actor: self.actor.clone(),
+                timestamp: self.timestamp.to_string(),
+                changes: Bson::Document(self.changes.clone()).into_relaxed_extjson(),
+                bulk: self.bulk.as_ref().map(|bulk| {
+                    let filter = Bson::Document(bulk.filter.clone()).into_relaxed_extjson();
+                    serde_json::json!({ "filter": filter, "matched": bulk.matched })
+                }),
+            }
+        }
+    }
+
+    /// `{ field: { before, after } }` for the top level fields that differ, a missing
+    /// document or field reads as null
+    pub fn diff(before: Option<&Document>, after: Option<&Document>) -> Document {
+        let empty = Document::new();
+        let (before, after) = (before.unwrap_or(&empty), after.unwrap_or(&empty));
+        let added = after.keys().filter(|key| !before.contains_key(key.as_str()));
+        let mut changes = Document::new();
+        for key in before.keys().chain(added) {
+            let (old, new) = (before.get(key), after.get(key));
+            if old != new {
+                changes.insert(
+                    key,
+                    doc! {
+                        "before": old.cloned().unwrap_or(Bson::Null),
+                        "after": new.cloned().unwrap_or(Bson::Null),
+                    },
+                );
+            }
+        }
+        changes
+    }
+
+    async fn find(
+        collection: &Collection<Document>,
+        filter: Document,
+        options: Option<FindOptions>,
+        session: Option<&mut ClientSession>,
+    ) -> Result<Vec<Document>> {
+        let Some(session) = session else {
+            let cursor = collection.find(filter, options).await?;
+            return Ok(cursor.try_collect().await?);
+        };
+        let mut cursor = collection
+            .find_with_session(filter, options, &mut *session)
+            .await?;
+        let mut documents = vec![];
+        while let Some(document) = cursor.next(&mut *session).await.transpose()? {
+            documents.push(document);
+        }
+        Ok(documents)
+    }
+
+    async fn count(
+        collection: &Collection<Document>,
+        filter: Document,
+        session: Option<&mut ClientSession>,
+    ) -> Result<u64> {
+        let count = match session {
+            Some(session) => {
+                collection
+                    .count_documents_with_session(filter, None, session)
+                    .await?
+            }
+            None => collection.count_documents(filter, None).await?,
+        };
+        Ok(count)
+    }
+
+    /// the most documents a write is audited one by one for: each is snapshotted in memory
+    /// and its id sent back with the write. larger writes are logged as a `BulkWrite`
+    pub const MAX_AUDITED_WRITE: usize = 1000;
+
+    /// how many documents a write may touch
+    #[derive(Debug, Clone, Copy)]
+    pub enum Reach {
+        One,
+        /// any number, doing `Operation` to each, which a bulk entry has to name up front
+        Many(Operation),
+    }
+
+    /// the documents a write is about to touch, so they can be compared once it's done
+    pub struct Trail {
+        /// `None` for models that opt out of auditing
+        collection: Option<Collection<Document>>,
+        before: Vec<Document>,
+        ids: Vec<Bson>,
+        /// set instead of the snapshot when the write matches too many documents
+        bulk: Option<(Operation, BulkWrite)>,
+    }
+
+    impl Trail {
+        pub const fn disabled() -> Self {
+            Self {
+                collection: None,
+                before: vec![],
+                ids: vec![],
+                bulk: None,
+            }
+        }
+
+        /// snapshots the documents matching `filter`, only the first one for `Reach::One`.
+        /// past `MAX_AUDITED_WRITE` matches it keeps the filter and a count instead
+        pub async fn begin(
+            collection: Collection<Document>,
+            filter: &Document,
+            reach: Reach,
+            mut session: Option<&mut ClientSession>,
+        ) -> Result<Self> {
+            let limit = match reach {
+                Reach::One => 1,
+                Reach::Many(_) => MAX_AUDITED_WRITE + 1,
+            };
+            let options = FindOptions::builder().limit(i64::try_from(limit)?).build();
+            let before = find(
+                &collection,
+                filter.clone(),
+                Some(options),
+                session.as_deref_mut(),
+            )
+            .await?;
+            if let (Reach::Many(operation), true) = (reach, before.len() > MAX_AUDITED_WRITE) {
+                let matched = count(&collection, filter.clone(), session).await?;
+                let bulk = BulkWrite {
+                    filter: filter.clone(),
+                    matched,
+                };
+                return Ok(Self {
+                    collection: Some(collection),
+                    before: vec![],
+                    ids: vec![],
+                    bulk: Some((operation, bulk)),
+                });
+            }
+            let ids = before
+                .iter()
+                .filter_map(|document| document.get("_id").cloned())
+                .collect();
+            Ok(Self {
+                collection: Some(collection),
+                before,
+                ids,
+                bulk: None,
+            })
+        }
+
+        /// a document that didn't exist before the write
+        pub fn inserted(collection: Collection<Document>, id: Bson) -> Self {
+            Self {
+                collection: Some(collection),
+                before: vec![],
+                ids: vec![id],
+                bulk: None,
+            }
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.ids.is_empty()
+        }
+
+        /// `filter` limited to the snapshotted documents, so the write can't reach one
+        /// that was created after the snapshot and would go unrecorded
+        pub fn narrow(&self, filter: Document) -> Document {
+            if self.collection.is_none() || self.bulk.is_some() {
+                return filter;
+            }
+            doc! { "$and": [filter, { "_id": { "$in": &self.ids } }] }
+        }
+
+        /// also tracks the document an upsert created
+        pub fn upserted(&mut self, id: Option<Bson>) {
+            self.ids.extend(id);
+        }
+
+        /// reads the documents back and logs an entry for each one that changed
+        pub async fn record(self, mut session: Option<&mut ClientSession>) -> Result<()> {
+            let Some(collection) = self.collection else {
+                return Ok(());
+            };
+            if let Some((operation, bulk)) = self.bulk {
+                let entry = AuditEntry {
+                    id: generate_nanoid(),
+                    collection: collection.name().to_string(),
+                    document_id: Bson::Null,
+                    operation,
+                    actor: current_actor(),
+                    timestamp: Utc::now(),
+                    changes: Document::new(),
+                    bulk: Some(bulk),
+                };
+                let log = AuditEntry::collection().await?;
+                match session {
+                    Some(session) => {
+                        log.insert_one_with_session(entry, None, session).await?;
+                    }
+                    None => {
+                        log.insert_one(entry, None).await?;
+                    }
+                }
+                return Ok(());
+            }
+            if self.ids.is_empty() {
+                return Ok(());
+            }
+            let filter = doc! { "_id": { "$in": &self.ids } };
+            let after = find(&collection, filter, None, session.as_deref_mut()).await?;
+            let by_id = |documents: Vec<Document>| {
+                documents
+                    .into_iter()
+                    .filter_map(|document| Some((document.get("_id")?.to_string(), document)))
+                    .collect::<HashMap<_, _>>()
+            };
+            let (before, after) = (by_id(self.before), by_id(after));
+            let actor = current_actor();
+            let timestamp = Utc::now();
+            let entries = self
+                .ids
+                .into_iter()
+                .filter_map(|id| {
+                    let key = id.to_string();
+                    let (before, after) = (before.get(&key), after.get(&key));
+                    let operation = match (before, after) {
+                        (None, Some(_)) => Operation::Insert,
+                        (Some(_), Some(_)) => Operation::Update,
+                        (Some(_), None) => Operation::Delete,
+                        (None, None) => return None,
+                    };
+                    let changes = diff(before, after);
+                    // updates that set fields to the values they already had
+                    if changes.is_empty() {
+                        return None;
+                    }
+                    Some(AuditEntry {
+                        id: generate_nanoid(),
+                        collection: collection.name().to_string(),
+                        document_id: id,
+                        operation,
+                        actor: actor.clone(),
+                        timestamp,
+                        changes,
+                        bulk: None,
+                    })
+                })
+                .collect::<Vec<_>>();
+            if entries.is_empty() {
+                return Ok(());
+            }
+            let log = AuditEntry::collection().await?;
+            match session {
+                Some(session) => {
+                    log.insert_many_with_session(entries, None, session).await?;
+                }
+                None => {
+                    log.insert_many(entries, None).await?;
+                }
+            }
+            Ok(())
+        }
+
+        /// `record` for writes outside a transaction: the write has already happened, so
+        /// failing to log it is reported rather than failing the request
+        pub async fn settle(self) {
+            let collection = self
+                .collection
+                .as_ref()
+                .map(|collection| collection.name().to_string());
+            if let Err(err) = self.record(None).await {
+                tracing::error!("recording audit entries for {collection:?}: {err:?}");
             }
-            return Ok(None);
         }
     }
 }
@@ -1,4 +1,5 @@
 pub mod api;
+pub mod middleware;
 pub mod models;
 pub mod prisma;
 pub mod prisma_models;
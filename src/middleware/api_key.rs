@@ -0,0 +1,118 @@
+use std::{rc::Rc, sync::Arc, time::Duration};
+
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    Error, HttpMessage,
+};
+use futures::future::{ready, LocalBoxFuture, Ready};
+
+use super::rate_limit::{Decision, Limit, Store};
+use crate::models::api_key::{seconds_until_reset, ApiKey, ApiKeyUsage, UsagePlan};
+use aws_rust::errors::ApiError;
+
+/// routes reachable without an api key
+const EXEMPT_PATHS: [&str; 3] = ["/api/health", "/api/admin", "/api/audit"];
+
+/// requires a valid `x-api-key` and enforces the daily quota and throttle of its tier,
+/// throttling through the same store as the rate limits so it holds across instances
+#[derive(Clone)]
+pub struct ApiKeys {
+    store: Arc<dyn Store>,
+}
+
+impl ApiKeys {
+    pub fn new(store: impl Store + 'static) -> Self {
+        Self {
+            store: Arc::new(store),
+        }
+    }
+}
+
+/// the tier's burst as a token bucket, holding `burst_limit` and refilling `rate_limit`
+/// tokens a second
+fn throttle(plan: UsagePlan) -> Limit {
+    let refill = f64::from(plan.burst_limit) / f64::from(plan.rate_limit);
+    Limit::token_bucket(plan.burst_limit, Duration::from_secs_f64(refill))
+}
+
+impl<S, B> Transform<S, ServiceRequest> for ApiKeys
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = ApiKeysMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ApiKeysMiddleware {
+            service: Rc::new(service),
+            store: Arc::clone(&self.store),
+        }))
+    }
+}
+
+pub struct ApiKeysMiddleware<S> {
+    service: Rc<S>,
+    store: Arc<dyn Store>,
+}
+
+/// checks the key on `req` and counts the request against its limits
+async fn admit(req: &ServiceRequest, store: &dyn Store) -> Result<ApiKey, ApiError> {
+    let key = req
+        .headers()
+        .get("x-api-key")
+        .and_then(|value| value.to_str().ok())
+        .ok_or_else(|| ApiError::Forbidden("missing api key".to_string()))?;
+    let api_key = ApiKey::find_active(key)
+        .await?
+        .ok_or_else(|| ApiError::Forbidden("invalid api key".to_string()))?;
+    let plan = api_key.tier.plan();
+    // throttle before counting so rejected bursts don't use up the daily quota
+    let key = format!("api_key:{}", api_key.id);
+    if let Decision::Limited { retry_after } = store.hit(&key, &throttle(plan)).await? {
+        return Err(ApiError::TooManyRequests {
+            message: "rate limit exceeded".to_string(),
+            retry_after,
+        });
+    }
+    if ApiKeyUsage::record(&api_key.id).await? > plan.quota_per_day {
+        return Err(ApiError::TooManyRequests {
+            message: "daily quota exceeded".to_string(),
+            retry_after: seconds_until_reset(),
+        });
+    }
+    Ok(api_key)
+}
+
+impl<S, B> Service<ServiceRequest> for ApiKeysMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        let store = Arc::clone(&self.store);
+        Box::pin(async move {
+            if !EXEMPT_PATHS.iter().any(|path| req.path().starts_with(path)) {
+                match admit(&req, store.as_ref()).await {
+                    Ok(api_key) => {
+                        req.extensions_mut().insert(api_key);
+                    }
+                    Err(err) => return Ok(req.error_response(err).map_into_right_body()),
+                }
+            }
+            let response = service.call(req).await?;
+            Ok(response.map_into_left_body())
+        })
+    }
+}
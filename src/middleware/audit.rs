@@ -0,0 +1,72 @@
+use std::rc::Rc;
+
+use actix_web::{
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    Error, FromRequest, HttpMessage,
+};
+use futures::future::{ready, LocalBoxFuture, Ready};
+
+use crate::models::api_key::ApiKey;
+use aws_rust::{
+    audit::with_actor,
+    auth::{Admin, AuthenticatedUser},
+};
+
+/// attributes the writes made while handling a request to its caller in the audit log
+#[derive(Clone, Copy, Default)]
+pub struct AuditActor;
+
+impl<S, B> Transform<S, ServiceRequest> for AuditActor
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = AuditActorMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AuditActorMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct AuditActorMiddleware<S> {
+    service: Rc<S>,
+}
+
+/// the most specific identity on `req`: a signed in user, then an admin, then an api key
+async fn actor(req: &ServiceRequest) -> Option<String> {
+    if let Ok(user) = AuthenticatedUser::extract(req.request()).await {
+        return Some(format!("user:{}", user.id));
+    }
+    if Admin::extract(req.request()).into_inner().is_ok() {
+        return Some("admin".to_string());
+    }
+    req.extensions()
+        .get::<ApiKey>()
+        .map(|api_key| format!("api_key:{}", api_key.id))
+}
+
+impl<S, B> Service<ServiceRequest> for AuditActorMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        Box::pin(async move {
+            let actor = actor(&req).await;
+            with_actor(actor, service.call(req)).await
+        })
+    }
+}
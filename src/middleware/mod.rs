@@ -0,0 +1,4 @@
+pub mod api_key;
+pub mod audit;
+pub mod rate_limit;
+pub mod throttle;
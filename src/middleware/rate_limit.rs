@@ -0,0 +1,472 @@
+use std::{
+    collections::HashMap,
+    rc::Rc,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::Method,
+    web, Error,
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use futures::future::{ready, LocalBoxFuture, Ready};
+
+use super::throttle::{retry_after, Throttle};
+use crate::models::rate_limit::{RateLimitBucket, RateLimitWindow};
+use aws_rust::{
+    auth::{bearer_token, decode_token},
+    config::Env,
+    errors::ApiError,
+};
+
+#[derive(Debug, Clone, Copy)]
+pub enum Algorithm {
+    /// weighs the previous fixed window by how much of it still overlaps the sliding one
+    SlidingWindow,
+    /// allows bursts of up to `requests`, refilled evenly over `period`
+    TokenBucket,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum KeyBy {
+    Ip,
+    /// the bearer token subject, falling back to the ip for anonymous requests
+    User,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Limit {
+    pub requests: u32,
+    pub period: Duration,
+    pub algorithm: Algorithm,
+    pub key_by: KeyBy,
+}
+
+impl Limit {
+    pub const fn sliding_window(requests: u32, period: Duration) -> Self {
+        Self {
+            requests,
+            period,
+            algorithm: Algorithm::SlidingWindow,
+            key_by: KeyBy::Ip,
+        }
+    }
+
+    pub const fn token_bucket(requests: u32, period: Duration) -> Self {
+        Self {
+            requests,
+            period,
+            algorithm: Algorithm::TokenBucket,
+            key_by: KeyBy::Ip,
+        }
+    }
+
+    #[must_use]
+    pub const fn per_user(self) -> Self {
+        Self {
+            key_by: KeyBy::User,
+            ..self
+        }
+    }
+
+    fn capacity(&self) -> f64 {
+        f64::from(self.requests)
+    }
+
+    /// tokens added back per second
+    fn rate(&self) -> f64 {
+        self.capacity() / self.period.as_secs_f64()
+    }
+
+    /// which fixed window `now` falls in and how far into it, as a fraction
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    fn window(&self, now: Duration) -> (u64, f64) {
+        let position = now.as_secs_f64() / self.period.as_secs_f64();
+        (position.floor() as u64, position.fract())
+    }
+
+    /// decides a sliding window request given the counts of the previous and current
+    /// fixed windows, where `current` already includes this request
+    #[allow(
+        clippy::cast_precision_loss,
+        clippy::cast_possible_truncation,
+        clippy::cast_sign_loss
+    )]
+    fn slide(&self, previous: i64, current: i64, elapsed: f64) -> Decision {
+        let (previous, current) = (previous as f64, current as f64);
+        if previous.mul_add(1.0 - elapsed, current) <= self.capacity() {
+            return Decision::Allowed;
+        }
+        // the estimate drops as the previous window slides out, unless this one is full
+        let free_at = if current > self.capacity() || previous == 0.0 {
+            1.0
+        } else {
+            1.0 - (self.capacity() - current) / previous
+        };
+        let wait = (free_at - elapsed) * self.period.as_secs_f64();
+        Decision::Limited {
+            retry_after: (wait.ceil() as u64).max(1),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Decision {
+    Allowed,
+    Limited { retry_after: u64 },
+}
+
+fn since_epoch() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+/// where counters are kept between requests
+#[async_trait]
+pub trait Store: Send + Sync {
+    /// counts a request from `key` against `limit`
+    async fn hit(&self, key: &str, limit: &Limit) -> Result<Decision>;
+}
+
+struct Window {
+    index: u64,
+    previous: i64,
+    current: i64,
+    /// once the window can no longer be read as the previous one
+    expires_at: Duration,
+}
+
+/// how often windows no longer counting towards any limit are dropped
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Default)]
+struct Windows {
+    by_key: HashMap<String, Window>,
+    swept_at: Duration,
+}
+
+impl Windows {
+    /// drops expired windows, at most once every `SWEEP_INTERVAL`, so keys of clients that
+    /// stopped sending requests don't pile up
+    fn sweep(&mut self, now: Duration) {
+        if now < self.swept_at + SWEEP_INTERVAL {
+            return;
+        }
+        self.by_key.retain(|_, window| window.expires_at > now);
+        self.swept_at = now;
+    }
+}
+
+/// counters held by this process, fine for the local server but not across lambda instances
+#[derive(Default)]
+pub struct MemoryStore {
+    buckets: Throttle,
+    windows: Mutex<Windows>,
+}
+
+#[async_trait]
+impl Store for MemoryStore {
+    async fn hit(&self, key: &str, limit: &Limit) -> Result<Decision> {
+        if matches!(limit.algorithm, Algorithm::TokenBucket) {
+            let decision = match self.buckets.acquire(key, limit.capacity(), limit.rate()) {
+                Ok(()) => Decision::Allowed,
+                Err(retry_after) => Decision::Limited { retry_after },
+            };
+            return Ok(decision);
+        }
+        let now = since_epoch();
+        let (index, elapsed) = limit.window(now);
+        let mut windows = self.windows.lock().unwrap_or_else(PoisonError::into_inner);
+        windows.sweep(now);
+        let window = windows.by_key.entry(key.to_string()).or_insert(Window {
+            index,
+            previous: 0,
+            current: 0,
+            expires_at: now,
+        });
+        // a window is still read as the previous one for a full period after it ends
+        window.expires_at = now + limit.period * 2;
+        if window.index != index {
+            window.previous = if window.index + 1 == index {
+                window.current
+            } else {
+                0
+            };
+            window.current = 0;
+            window.index = index;
+        }
+        window.current += 1;
+        Ok(limit.slide(window.previous, window.current, elapsed))
+    }
+}
+
+/// counters kept in mongo so a limit holds across every lambda instance
+#[derive(Default)]
+pub struct MongoStore;
+
+#[async_trait]
+impl Store for MongoStore {
+    async fn hit(&self, key: &str, limit: &Limit) -> Result<Decision> {
+        if matches!(limit.algorithm, Algorithm::TokenBucket) {
+            // an idle bucket is full again after one period, so it can expire then
+            let bucket =
+                RateLimitBucket::take(key, limit.capacity(), limit.rate(), limit.period).await?;
+            if bucket.allowed {
+                return Ok(Decision::Allowed);
+            }
+            return Ok(Decision::Limited {
+                retry_after: retry_after(bucket.tokens, limit.rate()),
+            });
+        }
+        let now = since_epoch();
+        let (index, elapsed) = limit.window(now);
+        // a window is still read as the previous one for a full period after it ends
+        let expires_at = SystemTime::UNIX_EPOCH + now + limit.period * 2;
+        let current =
+            RateLimitWindow::increment(&format!("{key}:{index}"), expires_at.into()).await?;
+        let previous =
+            RateLimitWindow::current(&format!("{key}:{}", index.saturating_sub(1))).await?;
+        Ok(limit.slide(previous, current, elapsed))
+    }
+}
+
+#[derive(Clone)]
+struct Rule {
+    method: Method,
+    path: &'static str,
+    limit: Limit,
+}
+
+impl Rule {
+    /// compares path segments, where a `{param}` segment matches anything
+    fn matches(&self, method: &Method, path: &str) -> bool {
+        let mut expected = self.path.trim_end_matches('/').split('/');
+        let mut actual = path.trim_end_matches('/').split('/');
+        if self.method != method {
+            return false;
+        }
+        loop {
+            match (expected.next(), actual.next()) {
+                (None, None) => return true,
+                (Some(expected), Some(actual))
+                    if expected == actual || expected.starts_with('{') => {}
+                _ => return false,
+            }
+        }
+    }
+}
+
+/// applies per-route limits keyed by client ip or authenticated user
+#[derive(Clone)]
+pub struct RateLimit {
+    store: Arc<dyn Store>,
+    rules: Arc<Vec<Rule>>,
+}
+
+impl RateLimit {
+    pub fn new(store: impl Store + 'static) -> Self {
+        Self {
+            store: Arc::new(store),
+            rules: Arc::new(vec![]),
+        }
+    }
+
+    /// limits `method` requests to `path`; the first matching route wins
+    #[must_use]
+    pub fn route(mut self, method: Method, path: &'static str, limit: Limit) -> Self {
+        Arc::make_mut(&mut self.rules).push(Rule {
+            method,
+            path,
+            limit,
+        });
+        self
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware {
+            service: Rc::new(service),
+            limiter: self.clone(),
+        }))
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: Rc<S>,
+    limiter: RateLimit,
+}
+
+/// the caller's ip; api gateway appends the source ip as the last `x-forwarded-for`
+/// entry, anything before it was sent by the client and can't be trusted
+fn client_ip(req: &ServiceRequest) -> String {
+    req.headers()
+        .get("x-forwarded-for")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.rsplit(',').next())
+        .map(str::trim)
+        .filter(|ip| !ip.is_empty())
+        .map(ToString::to_string)
+        .or_else(|| req.peer_addr().map(|addr| addr.ip().to_string()))
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+fn client_key(req: &ServiceRequest, key_by: KeyBy) -> String {
+    let user = match key_by {
+        KeyBy::Ip => None,
+        KeyBy::User => req
+            .app_data::<web::Data<Env>>()
+            .zip(bearer_token(req.headers()))
+            .and_then(|(env, token)| decode_token(env, token).ok()),
+    };
+    user.map_or_else(
+        || format!("ip:{}", client_ip(req)),
+        |claims| format!("user:{}", claims.sub),
+    )
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        let limiter = self.limiter.clone();
+        Box::pin(async move {
+            let rule = limiter
+                .rules
+                .iter()
+                .find(|rule| rule.matches(req.method(), req.path()));
+            if let Some(rule) = rule {
+                let key = format!(
+                    "{} {}|{}",
+                    rule.method,
+                    rule.path,
+                    client_key(&req, rule.limit.key_by)
+                );
+                match limiter.store.hit(&key, &rule.limit).await {
+                    Ok(Decision::Allowed) => {}
+                    Ok(Decision::Limited { retry_after }) => {
+                        let err = ApiError::TooManyRequests {
+                            message: "too many requests".to_string(),
+                            retry_after,
+                        };
+                        return Ok(req.error_response(err).map_into_right_body());
+                    }
+                    // an unreachable store shouldn't take the whole api down with it
+                    Err(err) => {
+                        tracing::warn!("rate limit store failed, allowing request: {err:?}")
+                    }
+                }
+            }
+            let response = service.call(req).await?;
+            Ok(response.map_into_left_body())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    fn limited(decision: Decision) -> Option<u64> {
+        match decision {
+            Decision::Allowed => None,
+            Decision::Limited { retry_after } => Some(retry_after),
+        }
+    }
+
+    #[test]
+    fn slide_weighs_the_previous_window_by_its_overlap() {
+        let limit = Limit::sliding_window(10, Duration::from_secs(60));
+        // 10 * 0.5 + 5 = 10 still fits, one more doesn't
+        assert_eq!(limited(limit.slide(10, 5, 0.5)), None);
+        assert_eq!(limited(limit.slide(10, 6, 0.5)), Some(6));
+        assert_eq!(limited(limit.slide(0, 10, 0.9)), None);
+    }
+
+    #[test]
+    fn slide_waits_for_the_next_window_when_the_current_one_is_full() {
+        let limit = Limit::sliding_window(10, Duration::from_secs(60));
+        assert_eq!(limited(limit.slide(0, 11, 0.25)), Some(45));
+        assert_eq!(limited(limit.slide(0, 11, 0.999)), Some(1));
+    }
+
+    #[test]
+    fn rule_matches_method_and_path_segments() {
+        let rule = Rule {
+            method: Method::POST,
+            path: "/api/todos/{id}/complete",
+            limit: Limit::sliding_window(1, Duration::from_secs(1)),
+        };
+        assert!(rule.matches(&Method::POST, "/api/todos/abc/complete"));
+        assert!(rule.matches(&Method::POST, "/api/todos/abc/complete/"));
+        assert!(!rule.matches(&Method::GET, "/api/todos/abc/complete"));
+        assert!(!rule.matches(&Method::POST, "/api/todos/abc"));
+        assert!(!rule.matches(&Method::POST, "/api/todos/abc/complete/extra"));
+        assert!(!rule.matches(&Method::POST, "/api/users/abc/complete"));
+    }
+
+    #[test]
+    fn client_ip_trusts_only_the_last_forwarded_entry() {
+        let req = TestRequest::default()
+            .insert_header(("x-forwarded-for", "10.0.0.1, 203.0.113.7"))
+            .to_srv_request();
+        assert_eq!(client_ip(&req), "203.0.113.7");
+    }
+
+    #[test]
+    fn client_ip_falls_back_to_the_peer_address() {
+        let req = TestRequest::default()
+            .peer_addr("198.51.100.2:443".parse().unwrap())
+            .to_srv_request();
+        assert_eq!(client_ip(&req), "198.51.100.2");
+        let req = TestRequest::default()
+            .insert_header(("x-forwarded-for", " "))
+            .to_srv_request();
+        assert_eq!(client_ip(&req), "unknown");
+    }
+
+    #[test]
+    fn sweep_drops_expired_windows() {
+        let mut windows = Windows::default();
+        for (key, expires_at) in [("old", 30), ("live", 90)] {
+            windows.by_key.insert(
+                key.to_string(),
+                Window {
+                    index: 0,
+                    previous: 0,
+                    current: 1,
+                    expires_at: Duration::from_secs(expires_at),
+                },
+            );
+        }
+        windows.sweep(Duration::from_secs(60));
+        assert!(!windows.by_key.contains_key("old"));
+        assert!(windows.by_key.contains_key("live"));
+    }
+}
@@ -0,0 +1,86 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+    capacity: f64,
+    rate: f64,
+}
+
+impl Bucket {
+    /// left alone until `now` it would be full, the same as a new bucket
+    fn is_full(&self, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        elapsed.mul_add(self.rate, self.tokens) >= self.capacity
+    }
+}
+
+/// how often idle buckets are dropped
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+struct Buckets {
+    by_key: HashMap<String, Bucket>,
+    swept_at: Instant,
+}
+
+impl Default for Buckets {
+    fn default() -> Self {
+        Self {
+            by_key: HashMap::new(),
+            swept_at: Instant::now(),
+        }
+    }
+}
+
+impl Buckets {
+    /// drops buckets that have refilled, at most once every `SWEEP_INTERVAL`, so keys that
+    /// stopped sending requests don't pile up
+    fn sweep(&mut self, now: Instant) {
+        if now.duration_since(self.swept_at) < SWEEP_INTERVAL {
+            return;
+        }
+        self.by_key.retain(|_, bucket| !bucket.is_full(now));
+        self.swept_at = now;
+    }
+}
+
+/// in-memory token buckets, one per key, shared by every worker of this instance
+#[derive(Clone, Default)]
+pub struct Throttle {
+    buckets: Arc<Mutex<Buckets>>,
+}
+
+impl Throttle {
+    /// takes a token from the bucket for `key`, holding at most `capacity` tokens and
+    /// refilling `rate` per second; returns the seconds until a token is available when empty
+    pub fn acquire(&self, key: &str, capacity: f64, rate: f64) -> Result<(), u64> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+        buckets.sweep(now);
+        let bucket = buckets.by_key.entry(key.to_string()).or_insert(Bucket {
+            tokens: capacity,
+            refilled_at: now,
+            capacity,
+            rate,
+        });
+        (bucket.capacity, bucket.rate) = (capacity, rate);
+        let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+        bucket.tokens = elapsed.mul_add(rate, bucket.tokens).min(capacity);
+        bucket.refilled_at = now;
+        if bucket.tokens < 1.0 {
+            return Err(retry_after(bucket.tokens, rate));
+        }
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+}
+
+/// whole seconds until a bucket holding `tokens` refills to one token at `rate` per second
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+pub fn retry_after(tokens: f64, rate: f64) -> u64 {
+    (((1.0 - tokens) / rate).ceil() as u64).max(1)
+}
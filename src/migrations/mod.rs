@@ -0,0 +1,377 @@
+use std::future::Future;
+
+use anyhow::{bail, Result};
+use bson::{doc, Document};
+use chrono::{DateTime, Duration, Utc};
+use mongodb::{
+    error::{Error as MongoError, ErrorKind},
+    options::UpdateOptions,
+    IndexModel,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    database::{database, generate_nanoid, ListQueryOptions, Model},
+    errors::is_duplicate_key,
+};
+
+pub mod registry;
+
+const LOCK_COLLECTION: &str = "_migrations_lock";
+const LOCK_ID: &str = "migrations";
+// long enough for a slow backfill, short enough that a crashed run doesn't block deploys for long
+const LOCK_TTL_MINUTES: i64 = 15;
+/// how often a running migration pushes the lock's expiry out again
+const LOCK_RENEW_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+const NAMESPACE_NOT_FOUND: i32 = 26;
+const INDEX_NOT_FOUND: i32 = 27;
+
+/// the index, or the whole collection, is already gone
+fn is_missing_index(err: &MongoError) -> bool {
+    matches!(
+        &*err.kind,
+        ErrorKind::Command(command)
+            if command.code == INDEX_NOT_FOUND || command.code == NAMESPACE_NOT_FOUND
+    )
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "step", rename_all = "snake_case")]
+pub enum Step {
+    CreateIndex {
+        collection: String,
+        index: Box<IndexModel>,
+    },
+    DropIndex {
+        collection: String,
+        name: String,
+    },
+    RenameField {
+        collection: String,
+        from: String,
+        to: String,
+    },
+    Backfill {
+        collection: String,
+        filter: Document,
+        update: Document,
+    },
+}
+
+impl Step {
+    pub fn create_index(collection: &str, index: IndexModel) -> Self {
+        Self::CreateIndex {
+            collection: collection.to_string(),
+            index: Box::new(index),
+        }
+    }
+
+    pub fn drop_index(collection: &str, name: &str) -> Self {
+        Self::DropIndex {
+            collection: collection.to_string(),
+            name: name.to_string(),
+        }
+    }
+
+    pub fn rename_field(collection: &str, from: &str, to: &str) -> Self {
+        Self::RenameField {
+            collection: collection.to_string(),
+            from: from.to_string(),
+            to: to.to_string(),
+        }
+    }
+
+    pub fn backfill(collection: &str, filter: Document, update: Document) -> Self {
+        Self::Backfill {
+            collection: collection.to_string(),
+            filter,
+            update,
+        }
+    }
+
+    /// safe to repeat, so a migration that failed partway can be run again from the start:
+    /// mongo accepts creating an index that already exists as declared, and dropping one
+    /// that's already gone counts as done
+    async fn apply(&self) -> Result<()> {
+        let database = database().await?;
+        match self {
+            Self::CreateIndex { collection, index } => {
+                database
+                    .collection::<Document>(collection)
+                    .create_index(*index.clone(), None)
+                    .await?;
+            }
+            Self::DropIndex { collection, name } => {
+                let dropped = database
+                    .collection::<Document>(collection)
+                    .drop_index(name.as_str(), None)
+                    .await;
+                match dropped {
+                    Err(err) if is_missing_index(&err) => {
+                        tracing::info!("index {collection}.{name} was already dropped");
+                    }
+                    dropped => dropped?,
+                }
+            }
+            Self::RenameField {
+                collection,
+                from,
+                to,
+            } => {
+                database
+                    .collection::<Document>(collection)
+                    .update_many(
+                        doc! { from.as_str(): { "$exists": true } },
+                        doc! { "$rename": { from.as_str(): to.as_str() } },
+                        None,
+                    )
+                    .await?;
+            }
+            Self::Backfill {
+                collection,
+                filter,
+                update,
+            } => {
+                database
+                    .collection::<Document>(collection)
+                    .update_many(filter.clone(), update.clone(), None)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    pub up: Vec<Step>,
+    pub down: Vec<Step>,
+}
+
+impl Migration {
+    /// fingerprint of the migration definition, used to detect edits after it was applied
+    pub fn checksum(&self) -> Result<String> {
+        let definition = doc! {
+            "version": self.version,
+            "name": self.name,
+            "up": bson::to_bson(&self.up)?,
+            "down": bson::to_bson(&self.down)?,
+        };
+        let mut bytes = vec![];
+        definition.to_writer(&mut bytes)?;
+        Ok(format!("{:x}", md5::compute(bytes)))
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Model)]
+#[model(collection = "_migrations", audit = false)]
+pub struct AppliedMigration {
+    #[serde(rename = "_id")]
+    pub version: i64,
+    pub name: String,
+    pub checksum: String,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub applied_at: DateTime<Utc>,
+}
+
+pub struct MigrationStatus {
+    pub version: i64,
+    pub name: &'static str,
+    pub applied_at: Option<DateTime<Utc>>,
+}
+
+/// single document lock so concurrent deploys don't apply the same migration twice
+struct MigrationLock {
+    owner: String,
+}
+
+impl MigrationLock {
+    async fn acquire() -> Result<Self> {
+        let owner = generate_nanoid();
+        let now = Utc::now();
+        let options = UpdateOptions::builder().upsert(true).build();
+        // an unexpired lock fails the filter, so the upsert collides on `_id`
+        let acquired = database()
+            .await?
+            .collection::<Document>(LOCK_COLLECTION)
+            .update_one(
+                doc! { "_id": LOCK_ID, "expires_at": { "$lt": now } },
+                doc! { "$set": {
+                    "owner": &owner,
+                    "expires_at": now + Duration::minutes(LOCK_TTL_MINUTES),
+                } },
+                options,
+            )
+            .await;
+        match acquired {
+            Ok(_) => Ok(Self { owner }),
+            Err(err) if is_duplicate_key(&err) => {
+                bail!("migrations are locked by another process")
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// pushes the expiry out again, failing if another process took the lock over
+    async fn renew(&self) -> Result<()> {
+        let renewed = database()
+            .await?
+            .collection::<Document>(LOCK_COLLECTION)
+            .update_one(
+                doc! { "_id": LOCK_ID, "owner": &self.owner },
+                doc! { "$set": {
+                    "expires_at": Utc::now() + Duration::minutes(LOCK_TTL_MINUTES),
+                } },
+                None,
+            )
+            .await?;
+        if renewed.matched_count == 0 {
+            bail!("lost the migrations lock to another process");
+        }
+        Ok(())
+    }
+
+    /// renews the lock every `LOCK_RENEW_INTERVAL` until that fails, returning why
+    async fn keep_alive(&self) -> anyhow::Error {
+        loop {
+            tokio::time::sleep(LOCK_RENEW_INTERVAL).await;
+            if let Err(err) = self.renew().await {
+                return err;
+            }
+        }
+    }
+
+    /// runs `work` while renewing the lock, so a long migration doesn't outlive it;
+    /// stops `work` if the lock is lost
+    async fn hold<T>(&self, work: impl Future<Output = Result<T>>) -> Result<T> {
+        tokio::select! {
+            result = work => result,
+            lost = self.keep_alive() => Err(lost),
+        }
+    }
+
+    async fn release(self) -> Result<()> {
+        database()
+            .await?
+            .collection::<Document>(LOCK_COLLECTION)
+            .delete_one(doc! { "_id": LOCK_ID, "owner": self.owner }, None)
+            .await?;
+        Ok(())
+    }
+}
+
+fn verify_order(migrations: &[Migration]) -> Result<()> {
+    for pair in migrations.windows(2) {
+        if pair[0].version >= pair[1].version {
+            bail!(
+                "migration {} must come before {}",
+                pair[1].version,
+                pair[0].version
+            );
+        }
+    }
+    Ok(())
+}
+
+async fn applied(migrations: &[Migration]) -> Result<Vec<AppliedMigration>> {
+    let opts = ListQueryOptions {
+        sort: Some(doc! { "_id": 1 }),
+        ..Default::default()
+    };
+    let applied = AppliedMigration::list(None::<Document>, Some(opts)).await?;
+    for record in &applied {
+        let Some(migration) = migrations.iter().find(|m| m.version == record.version) else {
+            bail!(
+                "applied migration {} {} is missing from the registry",
+                record.version,
+                record.name
+            );
+        };
+        if migration.checksum()? != record.checksum {
+            bail!(
+                "migration {} {} was modified after it was applied",
+                record.version,
+                record.name
+            );
+        }
+    }
+    Ok(applied)
+}
+
+async fn apply_pending(migrations: &[Migration]) -> Result<Vec<&'static str>> {
+    let applied = applied(migrations).await?;
+    let mut names = vec![];
+    for migration in migrations
+        .iter()
+        .filter(|m| !applied.iter().any(|record| record.version == m.version))
+    {
+        tracing::info!("applying migration {} {}", migration.version, migration.name);
+        for step in &migration.up {
+            step.apply().await?;
+        }
+        AppliedMigration {
+            version: migration.version,
+            name: migration.name.to_string(),
+            checksum: migration.checksum()?,
+            applied_at: Utc::now(),
+        }
+        .save()
+        .await?;
+        names.push(migration.name);
+    }
+    Ok(names)
+}
+
+async fn revert_latest(migrations: &[Migration], count: usize) -> Result<Vec<&'static str>> {
+    let applied = applied(migrations).await?;
+    let mut names = vec![];
+    for record in applied.iter().rev().take(count) {
+        let Some(migration) = migrations.iter().find(|m| m.version == record.version) else {
+            bail!("migration {} is missing from the registry", record.version);
+        };
+        tracing::info!("reverting migration {} {}", migration.version, migration.name);
+        for step in &migration.down {
+            step.apply().await?;
+        }
+        AppliedMigration::delete_one(doc! { "_id": migration.version }).await?;
+        names.push(migration.name);
+    }
+    Ok(names)
+}
+
+/// applies every pending migration in version order, returning the applied names
+pub async fn up(migrations: &[Migration]) -> Result<Vec<&'static str>> {
+    verify_order(migrations)?;
+    let lock = MigrationLock::acquire().await?;
+    let result = lock.hold(apply_pending(migrations)).await;
+    lock.release().await?;
+    result
+}
+
+/// reverts the `count` most recently applied migrations, returning the reverted names
+pub async fn down(migrations: &[Migration], count: usize) -> Result<Vec<&'static str>> {
+    verify_order(migrations)?;
+    let lock = MigrationLock::acquire().await?;
+    let result = lock.hold(revert_latest(migrations, count)).await;
+    lock.release().await?;
+    result
+}
+
+pub async fn status(migrations: &[Migration]) -> Result<Vec<MigrationStatus>> {
+    verify_order(migrations)?;
+    let applied = applied(migrations).await?;
+    let status = migrations
+        .iter()
+        .map(|migration| MigrationStatus {
+            version: migration.version,
+            name: migration.name,
+            applied_at: applied
+                .iter()
+                .find(|record| record.version == migration.version)
+                .map(|record| record.applied_at),
+        })
+        .collect();
+    Ok(status)
+}
@@ -0,0 +1,131 @@
+use std::time::Duration;
+
+use bson::{doc, Document};
+use mongodb::{options::IndexOptions, IndexModel};
+
+use super::{Migration, Step};
+
+fn index(keys: Document, unique: bool) -> IndexModel {
+    let options = unique.then(|| IndexOptions::builder().unique(true).build());
+    IndexModel::builder().keys(keys).options(options).build()
+}
+
+/// ttl index removing documents once their `expires_at` has passed
+fn expiry_index() -> IndexModel {
+    let options = IndexOptions::builder()
+        .expire_after(Duration::from_secs(0))
+        .build();
+    IndexModel::builder()
+        .keys(doc! { "expires_at": 1 })
+        .options(options)
+        .build()
+}
+
+/// unique index ignoring soft deleted documents, so their values can be reused
+fn live_unique_index(keys: Document) -> IndexModel {
+    let options = IndexOptions::builder()
+        .unique(true)
+        .partial_filter_expression(doc! { "deleted_at": null })
+        .build();
+    IndexModel::builder().keys(keys).options(options).build()
+}
+
+/// unique index over the documents that have the field, leaving older ones without it out
+fn sparse_unique_index(keys: Document) -> IndexModel {
+    let options = IndexOptions::builder().unique(true).sparse(true).build();
+    IndexModel::builder().keys(keys).options(options).build()
+}
+
+/// every migration in the order it must be applied; never edit one that has shipped,
+/// add a new version instead
+pub fn migrations() -> Vec<Migration> {
+    vec![
+        Migration {
+            version: 1,
+            name: "initial_indexes",
+            up: vec![
+                Step::create_index("todos", index(doc! { "complete": 1 }, false)),
+                Step::create_index("todos", index(doc! { "task": 1 }, true)),
+                Step::create_index("users", index(doc! { "username": 1 }, true)),
+                Step::create_index("users", index(doc! { "email": 1 }, true)),
+                Step::create_index("users", index(doc! { "todos": 1 }, true)),
+            ],
+            down: vec![
+                Step::drop_index("todos", "complete_1"),
+                Step::drop_index("todos", "task_1"),
+                Step::drop_index("users", "username_1"),
+                Step::drop_index("users", "email_1"),
+                Step::drop_index("users", "todos_1"),
+            ],
+        },
+        Migration {
+            version: 2,
+            name: "api_keys",
+            up: vec![
+                Step::create_index("api_keys", index(doc! { "hash": 1 }, true)),
+                Step::create_index("api_key_usage", expiry_index()),
+            ],
+            down: vec![
+                Step::drop_index("api_keys", "hash_1"),
+                Step::drop_index("api_key_usage", "expires_at_1"),
+            ],
+        },
+        Migration {
+            version: 3,
+            name: "rate_limits",
+            up: vec![
+                Step::create_index("rate_limit_windows", expiry_index()),
+                Step::create_index("rate_limit_buckets", expiry_index()),
+            ],
+            down: vec![
+                Step::drop_index("rate_limit_windows", "expires_at_1"),
+                Step::drop_index("rate_limit_buckets", "expires_at_1"),
+            ],
+        },
+        Migration {
+            version: 4,
+            name: "todos_text_search",
+            up: vec![Step::create_index(
+                "todos",
+                index(doc! { "task": "text" }, false),
+            )],
+            down: vec![Step::drop_index("todos", "task_text")],
+        },
+        Migration {
+            version: 5,
+            name: "audit_log",
+            up: vec![Step::create_index(
+                "audit_log",
+                index(
+                    doc! { "collection": 1, "document_id": 1, "timestamp": -1 },
+                    false,
+                ),
+            )],
+            down: vec![Step::drop_index(
+                "audit_log",
+                "collection_1_document_id_1_timestamp_-1",
+            )],
+        },
+        Migration {
+            version: 6,
+            name: "todos_task_unique_while_live",
+            up: vec![
+                Step::drop_index("todos", "task_1"),
+                Step::create_index("todos", live_unique_index(doc! { "task": 1 })),
+            ],
+            down: vec![
+                Step::drop_index("todos", "task_1"),
+                Step::create_index("todos", index(doc! { "task": 1 }, true)),
+            ],
+        },
+        Migration {
+            version: 7,
+            name: "users_account_id",
+            up: vec![Step::create_index(
+                "users",
+                sparse_unique_index(doc! { "account_id": 1 }),
+            )],
+            down: vec![Step::drop_index("users", "account_id_1")],
+        },
+    ]
+}
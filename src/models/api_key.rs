@@ -0,0 +1,153 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use bson::doc;
+use chrono::{DateTime, TimeZone, Utc};
+use mongodb::options::{FindOneAndUpdateOptions, ReturnDocument};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use aws_rust::database::{generate_nanoid, Model};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Tier {
+    Free,
+    Premium,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct UsagePlan {
+    pub quota_per_day: i64,
+    pub burst_limit: u32,
+    pub rate_limit: u32,
+}
+
+impl Tier {
+    /// mirrors the API Gateway usage plans in serverless.yml
+    pub const fn plan(self) -> UsagePlan {
+        match self {
+            Self::Free => UsagePlan {
+                quota_per_day: 100,
+                burst_limit: 10,
+                rate_limit: 20,
+            },
+            Self::Premium => UsagePlan {
+                quota_per_day: 1000,
+                burst_limit: 100,
+                rate_limit: 20,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, Model)]
+#[model(collection = "api_keys")]
+pub struct ApiKey {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub label: String,
+    pub tier: Tier,
+    /// sha256 of the key, the plaintext is only returned once when issued
+    #[index(unique)]
+    pub hash: String,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub created_at: DateTime<Utc>,
+    pub revoked_at: Option<bson::DateTime>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Normalized {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub label: String,
+    pub tier: Tier,
+    pub created_at: String,
+    pub revoked_at: Option<String>,
+}
+
+pub fn hash_key(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
+
+impl ApiKey {
+    pub fn normalize(&self) -> Normalized {
+        Normalized {
+            id: self.id.clone(),
+            label: self.label.clone(),
+            tier: self.tier,
+            created_at: self.created_at.to_string(),
+            revoked_at: self.revoked_at.map(|at| at.to_chrono().to_string()),
+        }
+    }
+
+    /// stores a new key and returns it along with the plaintext key
+    pub async fn issue(label: &str, tier: Tier) -> Result<(Self, String)> {
+        let key = format!("ak_{}{}", generate_nanoid(), generate_nanoid());
+        let api_key = Self {
+            id: generate_nanoid(),
+            label: label.to_string(),
+            tier,
+            hash: hash_key(&key),
+            created_at: Utc::now(),
+            revoked_at: None,
+        };
+        api_key.save().await?;
+        Ok((api_key, key))
+    }
+
+    /// the active key matching the plaintext `key`
+    pub async fn find_active(key: &str) -> Result<Option<Self>> {
+        let filter = doc! { "hash": hash_key(key), "revoked_at": null };
+        Self::read(Some(filter), None).await
+    }
+}
+
+/// requests made with a key on one utc day, shared by every instance
+#[derive(Debug, Deserialize, Serialize, Model)]
+// counted on every request
+#[model(collection = "api_key_usage", audit = false)]
+pub struct ApiKeyUsage {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub count: i64,
+    #[index(ttl = "0s")]
+    pub expires_at: bson::DateTime,
+}
+
+/// seconds until the daily quota resets at utc midnight
+pub fn seconds_until_reset() -> u64 {
+    let now = Utc::now();
+    let midnight = (now.date_naive() + chrono::Duration::days(1))
+        .and_hms_opt(0, 0, 0)
+        .map_or(now, |midnight| Utc.from_utc_datetime(&midnight));
+    u64::try_from((midnight - now).num_seconds()).unwrap_or(0)
+}
+
+impl ApiKeyUsage {
+    /// counts one request against today's quota and returns the new total
+    pub async fn record(key_id: &str) -> Result<i64> {
+        let now = Utc::now();
+        let id = format!("{key_id}:{}", now.format("%Y-%m-%d"));
+        // kept a day past the reset so the ttl monitor never races the current day
+        let expires_at = now
+            + chrono::Duration::from_std(Duration::from_secs(seconds_until_reset()))?
+            + chrono::Duration::days(1);
+        let options = FindOneAndUpdateOptions::builder()
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .build();
+        let usage = Self::collection()
+            .await?
+            .find_one_and_update(
+                doc! { "_id": id },
+                doc! {
+                    "$inc": { "count": 1 },
+                    "$setOnInsert": { "expires_at": expires_at },
+                },
+                options,
+            )
+            .await?;
+        Ok(usage.map_or(1, |usage| usage.count))
+    }
+}
@@ -1,2 +1,87 @@
+pub mod api_key;
+pub mod rate_limit;
 pub mod todo;
 pub mod user;
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use aws_rust::{
+        audit::AuditEntry,
+        database::Model,
+        migrations::{registry::migrations, Step},
+    };
+    use bson::{Bson, Document};
+    use mongodb::IndexModel;
+
+    use super::{
+        api_key::{ApiKey, ApiKeyUsage},
+        rate_limit::{RateLimitBucket, RateLimitWindow},
+        todo::Todo,
+        user::User,
+    };
+
+    /// what the migrations and the model attributes both declare about an index
+    type Shape = (Document, bool, bool, Option<u64>, Option<Document>);
+
+    fn name(index: &IndexModel) -> String {
+        if let Some(name) = index.options.as_ref().and_then(|options| options.name.clone()) {
+            return name;
+        }
+        let keys = index.keys.iter().map(|(key, value)| match value {
+            Bson::String(kind) => format!("{key}_{kind}"),
+            value => format!("{key}_{value}"),
+        });
+        keys.collect::<Vec<_>>().join("_")
+    }
+
+    fn shape(index: &IndexModel) -> Shape {
+        let options = index.options.clone().unwrap_or_default();
+        (
+            index.keys.clone(),
+            options.unique.unwrap_or_default(),
+            options.sparse.unwrap_or_default(),
+            options.expire_after.map(|after| after.as_secs()),
+            options.partial_filter_expression,
+        )
+    }
+
+    type Indexes = BTreeMap<(String, String), Shape>;
+
+    fn declared<M: Model>(indexes: &mut Indexes) {
+        for index in M::indexes() {
+            let key = (M::collection_name().to_string(), name(&index));
+            indexes.insert(key, shape(&index));
+        }
+    }
+
+    /// the model attributes and the migrations each describe the indexes, this keeps the
+    /// two from drifting apart
+    #[test]
+    fn declared_indexes_match_the_migrations() {
+        let mut migrated = Indexes::new();
+        for migration in migrations() {
+            for step in migration.up {
+                match step {
+                    Step::CreateIndex { collection, index } => {
+                        migrated.insert((collection, name(&index)), shape(&index));
+                    }
+                    Step::DropIndex { collection, name } => {
+                        migrated.remove(&(collection, name));
+                    }
+                    Step::RenameField { .. } | Step::Backfill { .. } => {}
+                }
+            }
+        }
+        let mut models = Indexes::new();
+        declared::<Todo>(&mut models);
+        declared::<User>(&mut models);
+        declared::<ApiKey>(&mut models);
+        declared::<ApiKeyUsage>(&mut models);
+        declared::<RateLimitWindow>(&mut models);
+        declared::<RateLimitBucket>(&mut models);
+        declared::<AuditEntry>(&mut models);
+        assert_eq!(models, migrated);
+    }
+}
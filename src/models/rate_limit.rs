@@ -0,0 +1,102 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use bson::doc;
+use mongodb::options::{FindOneAndUpdateOptions, ReturnDocument};
+use serde::{Deserialize, Serialize};
+
+use aws_rust::database::Model;
+
+fn upsert_after() -> FindOneAndUpdateOptions {
+    FindOneAndUpdateOptions::builder()
+        .upsert(true)
+        .return_document(ReturnDocument::After)
+        .build()
+}
+
+/// requests counted in one fixed window of a sliding window limit
+#[derive(Debug, Deserialize, Serialize, Model)]
+// counted on every limited request
+#[model(collection = "rate_limit_windows", audit = false)]
+pub struct RateLimitWindow {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub count: i64,
+    #[index(ttl = "0s")]
+    pub expires_at: bson::DateTime,
+}
+
+impl RateLimitWindow {
+    /// counts one request in the window `id` and returns the new total
+    pub async fn increment(id: &str, expires_at: bson::DateTime) -> Result<i64> {
+        let window = Self::collection()
+            .await?
+            .find_one_and_update(
+                doc! { "_id": id },
+                doc! {
+                    "$inc": { "count": 1 },
+                    "$setOnInsert": { "expires_at": expires_at },
+                },
+                upsert_after(),
+            )
+            .await?;
+        Ok(window.map_or(1, |window| window.count))
+    }
+
+    /// requests counted in the window `id`, zero when it has none
+    pub async fn current(id: &str) -> Result<i64> {
+        let window = Self::read(Some(doc! { "_id": id }), None).await?;
+        Ok(window.map_or(0, |window| window.count))
+    }
+}
+
+/// token bucket of a limit, refilled lazily whenever a request takes from it
+#[derive(Debug, Deserialize, Serialize, Model)]
+#[model(collection = "rate_limit_buckets", audit = false)]
+pub struct RateLimitBucket {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub tokens: f64,
+    /// whether the last request got a token
+    pub allowed: bool,
+    pub refilled_at: bson::DateTime,
+    #[index(ttl = "0s")]
+    pub expires_at: bson::DateTime,
+}
+
+impl RateLimitBucket {
+    /// refills the bucket `id` and takes a token when one is available, in a single
+    /// atomic update so concurrent instances never share a token
+    pub async fn take(id: &str, capacity: f64, rate: f64, ttl: Duration) -> Result<Self> {
+        let ttl_ms = i64::try_from(ttl.as_millis())?;
+        // timestamps come from the server clock so instances with skewed clocks agree
+        let pipeline = vec![
+            doc! { "$set": {
+                "tokens": { "$min": [
+                    capacity,
+                    { "$add": [
+                        { "$ifNull": ["$tokens", capacity] },
+                        { "$multiply": [
+                            { "$divide": [
+                                { "$subtract": ["$$NOW", { "$ifNull": ["$refilled_at", "$$NOW"] }] },
+                                1000,
+                            ] },
+                            rate,
+                        ] },
+                    ] },
+                ] },
+                "refilled_at": "$$NOW",
+                "expires_at": { "$add": ["$$NOW", ttl_ms] },
+            } },
+            doc! { "$set": { "allowed": { "$gte": ["$tokens", 1] } } },
+            doc! { "$set": {
+                "tokens": { "$cond": ["$allowed", { "$subtract": ["$tokens", 1] }, "$tokens"] },
+            } },
+        ];
+        let bucket = Self::collection()
+            .await?
+            .find_one_and_update(doc! { "_id": id }, pipeline, upsert_after())
+            .await?;
+        bucket.ok_or_else(|| anyhow::anyhow!("rate limit bucket {id} was not upserted"))
+    }
+}
@@ -1,22 +1,32 @@
 use anyhow::Result;
-use async_trait::async_trait;
-use bson::doc;
+use bson::{doc, Document};
 use chrono::{DateTime, Utc};
-use mongodb::{options::IndexOptions, results::CreateIndexesResult, IndexModel};
+use futures::TryStreamExt;
+use mongodb::options::FindOptions;
 use serde::{Deserialize, Serialize};
 
-use aws_rust::database::Model;
+use aws_rust::{
+    database::Model,
+    query::{Fields, IntoFilter},
+};
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize, Fields, Model)]
+#[model(collection = "todos", soft_delete)]
 pub struct Todo {
     #[serde(rename = "_id")]
     pub id: String,
+    // a collection holds one text index, add fields such as notes to this one
+    #[index(unique)]
+    #[index(text)]
     pub task: String,
+    #[index]
     pub complete: bool,
     #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
     pub created_at: DateTime<Utc>,
     #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
     pub updated_at: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<bson::DateTime>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -27,9 +37,130 @@ pub struct Normalized {
     pub complete: bool,
     pub created_at: String,
     pub updated_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Highlight {
+    /// character offsets into `task`
+    pub start: usize,
+    pub end: usize,
+    pub text: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SearchHit {
+    #[serde(flatten)]
+    pub todo: Normalized,
+    pub score: f64,
+    pub highlights: Vec<Highlight>,
+}
+
+/// the words and phrases a `$text` search looks for, leaving out negated ones
+pub fn search_terms(query: &str) -> Vec<String> {
+    let mut terms = vec![];
+    let mut rest = query.trim();
+    while !rest.is_empty() {
+        let negated = rest.starts_with('-');
+        let unsigned = rest.trim_start_matches('-');
+        let (term, remaining) = match unsigned.strip_prefix('"') {
+            Some(quoted) => quoted.split_once('"').unwrap_or((quoted, "")),
+            None => unsigned
+                .split_once(char::is_whitespace)
+                .unwrap_or((unsigned, "")),
+        };
+        if !negated && !term.trim().is_empty() {
+            terms.push(term.trim().to_lowercase());
+        }
+        rest = remaining.trim_start();
+    }
+    terms
+}
+
+/// spans of `text` matching `terms`, overlapping ones merged; single words also match
+/// longer words they start, roughly following the stemming mongo applies
+pub fn highlight(text: &str, terms: &[String]) -> Vec<Highlight> {
+    let lowered = text.to_lowercase();
+    let chars = lowered.chars().collect::<Vec<_>>();
+    let original = text.chars().collect::<Vec<_>>();
+    // lowercasing can change the length of some characters, offsets would drift
+    if chars.len() != original.len() {
+        return vec![];
+    }
+    let mut spans = vec![];
+    for term in terms {
+        let term = term.chars().collect::<Vec<_>>();
+        let phrase = term.iter().any(|c| c.is_whitespace());
+        let mut start = 0;
+        while start + term.len() <= chars.len() {
+            let at_word_start = start == 0 || !chars[start - 1].is_alphanumeric();
+            if at_word_start && chars[start..start + term.len()] == term[..] {
+                let mut end = start + term.len();
+                if !phrase {
+                    while end < chars.len() && chars[end].is_alphanumeric() {
+                        end += 1;
+                    }
+                }
+                spans.push((start, end));
+                start = end;
+            } else {
+                start += 1;
+            }
+        }
+    }
+    spans.sort_unstable();
+    let spans = spans.into_iter().fold(vec![], |mut merged: Vec<(usize, usize)>, span| {
+        match merged.last_mut() {
+            Some(last) if span.0 < last.1 => last.1 = last.1.max(span.1),
+            _ => merged.push(span),
+        }
+        merged
+    });
+    spans
+        .into_iter()
+        .map(|(start, end)| Highlight {
+            start,
+            end,
+            text: original[start..end].iter().collect(),
+        })
+        .collect()
 }
 
 impl Todo {
+    /// `$text` search within `filter`, best matches first
+    pub async fn search(
+        filter: impl IntoFilter<Self>,
+        query: &str,
+        limit: i64,
+    ) -> Result<Vec<SearchHit>> {
+        let mut filter = Self::scoped(Some(filter.into_filter()), false).unwrap_or_default();
+        filter.insert("$text", doc! { "$search": query });
+        let score = doc! { "score": { "$meta": "textScore" } };
+        let options = FindOptions::builder()
+            .projection(score.clone())
+            .sort(score)
+            .limit(limit)
+            .build();
+        let terms = search_terms(query);
+        let mut cursor = Self::collection()
+            .await?
+            .clone_with_type::<Document>()
+            .find(filter, options)
+            .await?;
+        let mut hits = vec![];
+        while let Some(mut document) = cursor.try_next().await? {
+            let score = document.remove("score").and_then(|score| score.as_f64());
+            let todo = bson::from_document::<Self>(document)?;
+            hits.push(SearchHit {
+                highlights: highlight(&todo.task, &terms),
+                todo: todo.normalize(),
+                score: score.unwrap_or_default(),
+            });
+        }
+        Ok(hits)
+    }
+
     pub fn normalize(&self) -> Normalized {
         Normalized {
             id: self.id.clone(),
@@ -37,30 +168,63 @@ impl Todo {
             complete: self.complete,
             created_at: self.created_at.to_string(),
             updated_at: self.updated_at.to_string(),
+            deleted_at: self.deleted_at.map(|at| at.to_chrono().to_string()),
         }
     }
 }
 
-#[async_trait]
-impl Model for Todo {
-    fn collection_name<'a>() -> &'a str {
-        "todos"
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spans(text: &str, query: &str) -> Vec<(usize, usize, String)> {
+        highlight(text, &search_terms(query))
+            .into_iter()
+            .map(|highlight| (highlight.start, highlight.end, highlight.text))
+            .collect()
     }
 
-    async fn create_indexes() -> Result<Option<CreateIndexesResult>> {
-        let complete_index = IndexModel::builder()
-            .keys(doc! { "complete": 1 })
-            .options(None)
-            .build();
-        let task_index = IndexModel::builder()
-            .keys(doc! { "task": 1 })
-            .options(IndexOptions::builder().unique(true).build())
-            .build();
-        let indexes = [complete_index, task_index];
-        let result = Self::collection()
-            .await
-            .create_indexes(indexes, None)
-            .await?;
-        Ok(Some(result))
+    #[test]
+    fn search_terms_keeps_phrases_and_drops_negated_terms() {
+        assert_eq!(
+            search_terms(r#"Buy "oat milk" -soy -"cow milk""#),
+            vec!["buy", "oat milk"]
+        );
+        assert_eq!(search_terms(r#"  "unterminated phrase"#), vec!["unterminated phrase"]);
+        assert!(search_terms("  -only ").is_empty());
+    }
+
+    #[test]
+    fn highlight_folds_case_and_extends_words() {
+        assert_eq!(
+            spans("Call MUM, then mummy", "mum"),
+            vec![(5, 8, "MUM".to_string()), (15, 20, "mummy".to_string())]
+        );
+        // only at the start of a word
+        assert!(spans("drum", "rum").is_empty());
+    }
+
+    #[test]
+    fn highlight_offsets_count_characters() {
+        assert_eq!(
+            spans("Café crème brûlée", r#""crème brûlée""#),
+            vec![(5, 17, "crème brûlée".to_string())]
+        );
+        assert_eq!(spans("日本語 テスト", "テスト"), vec![(4, 7, "テスト".to_string())]);
+    }
+
+    #[test]
+    fn highlight_merges_overlapping_terms() {
+        assert_eq!(
+            spans("buy oat milk", r#""oat milk" milk oat"#),
+            vec![(4, 12, "oat milk".to_string())]
+        );
+        assert_eq!(spans("todo", "to todo"), vec![(0, 4, "todo".to_string())]);
+    }
+
+    #[test]
+    fn highlight_gives_up_when_lowercasing_changes_lengths() {
+        // `İ` lowercases to two characters, so offsets into the original would drift
+        assert!(spans("İstanbul trip", "trip").is_empty());
     }
 }
@@ -1,21 +1,34 @@
 use anyhow::Result;
-use async_trait::async_trait;
-use bson::doc;
 use chrono::{DateTime, Utc};
-use mongodb::{options::IndexOptions, results::CreateIndexesResult, IndexModel};
 use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
 use serde::{Deserialize, Serialize};
 
 use super::todo::{Normalized as NormalizedTodo, Todo};
-use aws_rust::database::Model;
+use aws_rust::{
+    auth::AuthenticatedUser,
+    database::{Model, Ref},
+    errors::ApiError,
+    query::Fields,
+};
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Fields, Model)]
+#[model(collection = "users")]
 pub struct User {
     #[serde(rename = "_id")]
     pub id: String,
+    /// the planetscale account this profile belongs to, the `sub` of its tokens; profiles
+    /// made before it was recorded have none and stay unreachable until it is set
+    #[serde(default)]
+    #[index(unique, sparse)]
+    pub account_id: String,
+    #[index(unique)]
     pub username: String,
+    #[index(unique)]
     pub email: String,
-    pub todos: Vec<String>,
+    /// the user's todos, soft deleted ones included until they are purged so they can be
+    /// restored; populating leaves those as bare ids and `normalize` drops them
+    #[index(unique)]
+    pub todos: Vec<Ref<Todo>>,
     #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
     pub created_at: DateTime<Utc>,
     #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
@@ -23,24 +36,42 @@ pub struct User {
 }
 
 #[derive(Debug, Deserialize, Serialize)]
-pub struct Populated {
+pub struct Normalized {
     #[serde(rename = "_id")]
     pub id: String,
     pub username: String,
     pub email: String,
-    pub todos: Vec<Todo>,
-    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
-    pub created_at: DateTime<Utc>,
-    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
-    pub updated_at: DateTime<Utc>,
+    /// the populated todos, references that weren't populated are left out
+    pub todos: Vec<NormalizedTodo>,
+    pub created_at: String,
+    pub updated_at: String,
 }
 
-impl Populated {
+impl User {
+    /// the mongo profile of the authenticated caller, linked by account id; the email on a
+    /// token isn't verified, so it must never decide whose profile this is
+    pub async fn for_caller(caller: &AuthenticatedUser) -> Result<Self, ApiError> {
+        let found = Self::read(Some(Self::ACCOUNT_ID.eq(&caller.id)), None).await?;
+        found.ok_or_else(|| ApiError::Forbidden("no user profile for this account".to_string()))
+    }
+
+    /// ok when this user owns the todo, 403 when someone else does, 404 when nobody does
+    pub async fn authorize_todo(&self, id: &str) -> Result<(), ApiError> {
+        if self.todos.iter().any(|todo| todo.refers_to(id)) {
+            return Ok(());
+        }
+        match Todo::read(Some(Todo::ID.eq(id)), None).await? {
+            Some(_) => Err(ApiError::Forbidden("todo belongs to another user".to_string())),
+            None => Err(ApiError::NotFound("no todo found".to_string())),
+        }
+    }
+
     pub fn normalize(&self) -> Normalized {
         let todos = self
             .todos
             .par_iter()
-            .map(super::todo::Todo::normalize)
+            .filter_map(Ref::document)
+            .map(Todo::normalize)
             .collect::<Vec<_>>();
         Normalized {
             id: self.id.clone(),
@@ -52,42 +83,3 @@ impl Populated {
         }
     }
 }
-
-#[derive(Debug, Deserialize, Serialize)]
-pub struct Normalized {
-    #[serde(rename = "_id")]
-    pub id: String,
-    pub username: String,
-    pub email: String,
-    pub todos: Vec<NormalizedTodo>,
-    pub created_at: String,
-    pub updated_at: String,
-}
-
-#[async_trait]
-impl Model for User {
-    fn collection_name<'a>() -> &'a str {
-        "users"
-    }
-
-    async fn create_indexes() -> Result<Option<CreateIndexesResult>> {
-        let username_index = IndexModel::builder()
-            .keys(doc! { "username": 1 })
-            .options(IndexOptions::builder().unique(true).build())
-            .build();
-        let email_index = IndexModel::builder()
-            .keys(doc! { "email": 1 })
-            .options(IndexOptions::builder().unique(true).build())
-            .build();
-        let todos_index = IndexModel::builder()
-            .keys(doc! { "todos": 1 })
-            .options(IndexOptions::builder().unique(true).build())
-            .build();
-        let indexes = [username_index, email_index, todos_index];
-        let result = Self::collection()
-            .await
-            .create_indexes(indexes, None)
-            .await?;
-        Ok(Some(result))
-    }
-}
@@ -1,25 +1,46 @@
 use anyhow::Result;
-use async_once::AsyncOnce;
 use async_trait::async_trait;
 use lazy_static::lazy_static;
 use serde::Deserialize;
+use tokio::sync::OnceCell;
 
+pub mod password_reset_model;
 pub mod user_model;
 
 use crate::prisma::{self, PrismaClient};
+use aws_rust::{
+    config::Env,
+    database::{connect_with_backoff, misconfigured, CONNECT_TIMEOUT},
+};
 
 lazy_static! {
-    #[derive(Debug, Clone, Copy)]
-    pub static ref PRISMA_CLIENT: AsyncOnce<PrismaClient> = AsyncOnce::new(async {
-        tracing::info!("connecting to mysql...");
-        match prisma::new_client().await {
-            Ok(client) => client,
-            Err(err) => {
-                tracing::error!("connecting to mysql db: {err}");
-                std::process::exit(1)
-            }
-        }
-    });
+    static ref PRISMA_CLIENT: OnceCell<PrismaClient> = OnceCell::new();
+}
+
+async fn connect() -> Result<PrismaClient> {
+    tracing::info!("connecting to mysql...");
+    let env = Env::global().map_err(|err| misconfigured("mysql", err))?;
+    // prisma reads its pool size and connect timeout from the connection string
+    let separator = if env.database_url.contains('?') { '&' } else { '?' };
+    let url = format!(
+        "{}{separator}connection_limit={}&connect_timeout={}",
+        env.database_url,
+        env.mysql_pool_size,
+        CONNECT_TIMEOUT.as_secs()
+    );
+    Ok(prisma::new_client_with_url(&url).await?)
+}
+
+pub async fn client() -> Result<&'static PrismaClient> {
+    PRISMA_CLIENT
+        .get_or_try_init(|| connect_with_backoff("mysql", connect))
+        .await
+}
+
+/// cheapest query the generated client offers, used by readiness checks
+pub async fn ping() -> Result<()> {
+    client().await?.user().find_first(vec![]).exec().await?;
+    Ok(())
 }
 
 #[derive(Deserialize)]
@@ -0,0 +1,203 @@
+use anyhow::Result;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{Duration, Utc};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::{client, user_model::hash_password};
+use crate::prisma::{password_reset, user};
+use aws_rust::validation::{Validate, Validator};
+
+pub const RESET_TOKEN_TTL_MINUTES: i64 = 60;
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ChangePassword {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+impl Validate for ChangePassword {
+    fn validate(&self, validator: &mut Validator) {
+        validator
+            .length("current_password", &self.current_password, 1, 128)
+            .length("new_password", &self.new_password, 8, 128);
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RequestPasswordReset {
+    pub email: String,
+}
+
+impl Validate for RequestPasswordReset {
+    fn normalize(&mut self) {
+        self.email = self.email.trim().to_lowercase();
+    }
+
+    fn validate(&self, validator: &mut Validator) {
+        validator.email("email", &self.email);
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ConfirmPasswordReset {
+    pub token: String,
+    pub password: String,
+}
+
+impl Validate for ConfirmPasswordReset {
+    fn normalize(&mut self) {
+        self.token = self.token.trim().to_string();
+    }
+
+    fn validate(&self, validator: &mut Validator) {
+        validator
+            .length("token", &self.token, 1, 128)
+            .length("password", &self.password, 8, 128);
+    }
+}
+
+/// only the digest is stored, so a leaked table can't be used to reset passwords
+pub fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+impl password_reset::Data {
+    /// stores a reset for the user with `email` and returns the user with the plaintext
+    /// token, or `None` when no user has that email
+    pub async fn issue(email: &str) -> Result<Option<(user::Data, String)>> {
+        let client = client().await?;
+        let Some(user) = client
+            .user()
+            .find_unique(user::email::equals(email.to_string()))
+            .exec()
+            .await?
+        else {
+            return Ok(None);
+        };
+        let token = generate_token();
+        let expires_at = Utc::now() + Duration::minutes(RESET_TOKEN_TTL_MINUTES);
+        client
+            .password_reset()
+            .create(
+                hash_token(&token),
+                expires_at.into(),
+                password_reset::user::connect(user::id::equals(user.id.clone())),
+                vec![],
+            )
+            .exec()
+            .await?;
+        Ok(Some((user, token)))
+    }
+}
+
+impl user::Data {
+    /// hashes and stores a new password, voiding any reset tokens and access tokens still
+    /// outstanding
+    pub async fn set_password(id: &str, password: &str) -> Result<Self> {
+        let client = client().await?;
+        let password = hash_password(password)?;
+        let now = Utc::now();
+        let updated_user: Self = client
+            ._transaction()
+            .run(|client| async move {
+                let updated_user = client
+                    .user()
+                    .update(
+                        user::id::equals(id.to_string()),
+                        vec![
+                            user::password::set(password),
+                            user::token_version::increment(1),
+                        ],
+                    )
+                    .exec()
+                    .await?;
+                client
+                    .password_reset()
+                    .update_many(
+                        vec![
+                            password_reset::user_id::equals(id.to_string()),
+                            password_reset::used_at::equals(None),
+                        ],
+                        vec![password_reset::used_at::set(Some(now.into()))],
+                    )
+                    .exec()
+                    .await?;
+                Ok(updated_user) as Result<_, prisma_client_rust::QueryError>
+            })
+            .await?;
+        Ok(updated_user)
+    }
+
+    /// consumes a reset token and sets the new password, or `None` when the token is
+    /// unknown, expired or already used
+    pub async fn reset_password(token: &str, password: &str) -> Result<Option<Self>> {
+        let client = client().await?;
+        let Some(reset) = client
+            .password_reset()
+            .find_unique(password_reset::token_hash::equals(hash_token(token)))
+            .exec()
+            .await?
+        else {
+            return Ok(None);
+        };
+        let now = Utc::now();
+        if reset.used_at.is_some() || reset.expires_at < now {
+            return Ok(None);
+        }
+        // hashed up front so the transaction isn't held open while argon2 runs
+        let password = hash_password(password)?;
+        let updated_user: Option<Self> = client
+            ._transaction()
+            .run(|client| async move {
+                // the guards make a concurrent confirm with the same token a no-op
+                let consumed = client
+                    .password_reset()
+                    .update_many(
+                        vec![
+                            password_reset::id::equals(reset.id),
+                            password_reset::used_at::equals(None),
+                            password_reset::expires_at::gt(now.into()),
+                        ],
+                        vec![password_reset::used_at::set(Some(now.into()))],
+                    )
+                    .exec()
+                    .await?;
+                if consumed == 0 {
+                    return Ok(None);
+                }
+                let updated_user = client
+                    .user()
+                    .update(
+                        user::id::equals(reset.user_id.clone()),
+                        vec![
+                            user::password::set(password),
+                            user::token_version::increment(1),
+                        ],
+                    )
+                    .exec()
+                    .await?;
+                client
+                    .password_reset()
+                    .update_many(
+                        vec![
+                            password_reset::user_id::equals(reset.user_id),
+                            password_reset::used_at::equals(None),
+                        ],
+                        vec![password_reset::used_at::set(Some(now.into()))],
+                    )
+                    .exec()
+                    .await?;
+                Ok(Some(updated_user)) as Result<_, prisma_client_rust::QueryError>
+            })
+            .await?;
+        Ok(updated_user)
+    }
+}
@@ -1,12 +1,23 @@
 use anyhow::Result;
 use argon2::Config;
 use async_trait::async_trait;
+use chrono::{DateTime, FixedOffset};
+use lazy_static::lazy_static;
 use rand::{rngs::OsRng, RngCore};
 use serde::{Deserialize, Serialize};
 use slug::slugify;
 
-use super::{PaginationQuery, PrismaHelpers, PRISMA_CLIENT};
-use crate::prisma::{address, user};
+use super::{client, PaginationQuery, PrismaHelpers};
+use crate::prisma::{address, password_reset, user};
+use aws_rust::{
+    auth::TokenVersions,
+    validation::{trim, Validate, Validator},
+};
+
+lazy_static! {
+    // verified against when an email is unknown, so a miss costs the same as a wrong password
+    static ref DUMMY_HASH: Option<String> = hash_password("dummy-password-for-timing").ok();
+}
 
 #[async_trait]
 impl PrismaHelpers<Self> for user::Data {
@@ -25,7 +36,7 @@ impl PrismaHelpers<Self> for user::Data {
                 limit
             }
         });
-        let client = PRISMA_CLIENT.get().await;
+        let client = client().await?;
         let results = client
             .user()
             .find_many(vec![])
@@ -38,7 +49,7 @@ impl PrismaHelpers<Self> for user::Data {
     }
 
     async fn read_by_id(id: &str) -> Result<Option<Self>> {
-        let client = PRISMA_CLIENT.get().await;
+        let client = client().await?;
         let user = client
             .user()
             .find_first(vec![user::id::equals(id.to_string())])
@@ -49,6 +60,22 @@ impl PrismaHelpers<Self> for user::Data {
     }
 }
 
+/// reads token versions from the users table, for checking access tokens
+pub struct UserTokenVersions;
+
+#[async_trait]
+impl TokenVersions for UserTokenVersions {
+    async fn current(&self, subject: &str) -> Result<Option<i32>> {
+        let user = client()
+            .await?
+            .user()
+            .find_unique(user::id::equals(subject.to_string()))
+            .exec()
+            .await?;
+        Ok(user.map(|user| user.token_version))
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct CreateAddress {
     pub address: i32,
@@ -69,6 +96,83 @@ pub struct CreateUser {
     pub addresses: CreateAddress,
 }
 
+impl Validate for CreateAddress {
+    fn normalize(&mut self) {
+        trim(&mut self.street);
+        trim(&mut self.city);
+        trim(&mut self.state);
+        trim(&mut self.zip);
+        trim(&mut self.country);
+        self.apt_number = self
+            .apt_number
+            .as_deref()
+            .map(str::trim)
+            .filter(|apt| !apt.is_empty())
+            .map(ToString::to_string);
+    }
+
+    fn validate(&self, validator: &mut Validator) {
+        validator
+            .range("address", self.address, 1, 999_999)
+            .length("street", &self.street, 1, 128)
+            .length("city", &self.city, 1, 64)
+            .length("state", &self.state, 1, 64)
+            .length("zip", &self.zip, 1, 16)
+            .length("country", &self.country, 1, 64);
+        if let Some(apt_number) = &self.apt_number {
+            validator.length("apt_number", apt_number, 1, 16);
+        }
+    }
+}
+
+impl Validate for CreateUser {
+    fn normalize(&mut self) {
+        self.email = self.email.trim().to_lowercase();
+        trim(&mut self.first_name);
+        trim(&mut self.last_name);
+        self.addresses.normalize();
+    }
+
+    fn validate(&self, validator: &mut Validator) {
+        validator
+            .email("email", &self.email)
+            .length("first_name", &self.first_name, 1, 64)
+            .length("last_name", &self.last_name, 1, 64)
+            .length("password", &self.password, 8, 128)
+            .nested("addresses", &self.addresses);
+    }
+}
+
+/// what the api returns for a user, everything but the password hash and token version
+#[derive(Debug, Serialize)]
+pub struct Profile {
+    pub id: String,
+    pub email: String,
+    pub first_name: String,
+    pub last_name: String,
+    pub avatar_hash: String,
+    pub slug: String,
+    pub created_at: DateTime<FixedOffset>,
+    pub updated_at: DateTime<FixedOffset>,
+    pub addresses: Option<Vec<address::Data>>,
+}
+
+impl From<user::Data> for Profile {
+    fn from(user: user::Data) -> Self {
+        Self {
+            id: user.id,
+            email: user.email,
+            first_name: user.first_name,
+            last_name: user.last_name,
+            avatar_hash: user.avatar_hash,
+            slug: user.slug,
+            created_at: user.created_at,
+            updated_at: user.updated_at,
+            addresses: user.addresses,
+        }
+    }
+}
+
 pub fn hash_password(password: &str) -> anyhow::Result<String> {
     let mut salt = [0u8; 32];
     OsRng.fill_bytes(&mut salt);
@@ -76,6 +180,29 @@ pub fn hash_password(password: &str) -> anyhow::Result<String> {
     Ok(argon2::hash_encoded(password.as_bytes(), &salt, &config)?)
 }
 
+/// argon2 verification compares digests in constant time
+pub fn verify_password(hash: &str, password: &str) -> Result<bool> {
+    Ok(argon2::verify_encoded(hash, password.as_bytes())?)
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Login {
+    pub email: String,
+    pub password: String,
+}
+
+impl Validate for Login {
+    fn normalize(&mut self) {
+        self.email = self.email.trim().to_lowercase();
+    }
+
+    fn validate(&self, validator: &mut Validator) {
+        validator
+            .email("email", &self.email)
+            .length("password", &self.password, 1, 128);
+    }
+}
+
 pub fn create_user_slug(first_name: &str, last_name: &str) -> String {
     slugify(format!("{first_name} {last_name}"))
 }
@@ -88,7 +215,7 @@ pub fn generate_gravatar_hash(email: &str) -> String {
 
 impl user::Data {
     pub async fn create(input: CreateUser) -> Result<Self> {
-        let client = PRISMA_CLIENT.get().await;
+        let client = client().await?;
         let password = hash_password(&input.password)?;
         let avatar_hash = generate_gravatar_hash(&input.email);
         let slug = create_user_slug(&input.first_name, &input.last_name);
@@ -137,6 +264,7 @@ impl user::Data {
                     last_name: data.last_name,
                     avatar_hash: data.avatar_hash,
                     password: data.password,
+                    token_version: data.token_version,
                     slug: data.slug,
                     created_at: data.created_at,
                     updated_at: data.updated_at,
@@ -147,8 +275,28 @@ impl user::Data {
         Ok(inserted_user)
     }
 
+    /// the user matching `email` and `password`, or `None` for an unknown email or wrong password
+    pub async fn authenticate(email: &str, password: &str) -> Result<Option<Self>> {
+        let client = client().await?;
+        let found = client
+            .user()
+            .find_unique(user::email::equals(email.to_string()))
+            .exec()
+            .await?;
+        match found {
+            Some(user) if verify_password(&user.password, password)? => Ok(Some(user)),
+            Some(_) => Ok(None),
+            None => {
+                if let Some(hash) = DUMMY_HASH.as_deref() {
+                    verify_password(hash, password)?;
+                }
+                Ok(None)
+            }
+        }
+    }
+
     pub async fn delete(id: &str) -> Result<Self> {
-        let client = PRISMA_CLIENT.get().await;
+        let client = client().await?;
         let removed_user: Self = client
             ._transaction()
             .run(|client| async move {
@@ -157,6 +305,11 @@ impl user::Data {
                     .delete_many(vec![address::user_id::equals(Some(id.to_string()))])
                     .exec()
                     .await?;
+                client
+                    .password_reset()
+                    .delete_many(vec![password_reset::user_id::equals(id.to_string())])
+                    .exec()
+                    .await?;
                 let removed_user = client
                     .user()
                     .delete(user::id::equals(id.to_string()))
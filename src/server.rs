@@ -1,32 +1,128 @@
-use actix_web::{web::scope, App, HttpServer};
+use std::{sync::Arc, time::Duration};
+
+use actix_cors::Cors;
+use actix_web::{
+    http::Method,
+    middleware::Condition,
+    web::{self, scope},
+    App, HttpServer,
+};
 use lambda_web::{is_running_on_lambda, run_actix_on_lambda};
 use tracing_subscriber::FmtSubscriber;
 
 use crate::{
     api,
-    models::{todo::Todo, user::User},
+    middleware::{
+        api_key::ApiKeys,
+        audit::AuditActor,
+        rate_limit::{Limit, MemoryStore, MongoStore, RateLimit},
+    },
+    prisma_models::user_model::UserTokenVersions,
 };
 use aws_rust::{
-    config::{self, Env},
-    database::Model,
+    auth::TokenVersions,
+    config::{Env, RateLimitStore},
+    errors::ApiError,
+    notify,
 };
 
-pub async fn run() -> anyhow::Result<(), lambda_http::Error> {
-    let Env { log_level, .. } = config::Env::default();
-    {
-        // migrate indexes
-        Todo::create_indexes().await?;
-        User::create_indexes().await?;
+const MINUTE: Duration = Duration::from_secs(60);
+
+fn api_keys(store: RateLimitStore) -> ApiKeys {
+    match store {
+        RateLimitStore::Memory => ApiKeys::new(MemoryStore::default()),
+        RateLimitStore::Mongo => ApiKeys::new(MongoStore),
     }
-    let subscriber = FmtSubscriber::builder().with_max_level(log_level).finish();
+}
+
+/// limits for the routes that are expensive or easy to abuse
+fn rate_limit(store: RateLimitStore) -> RateLimit {
+    let limiter = match store {
+        RateLimitStore::Memory => RateLimit::new(MemoryStore::default()),
+        RateLimitStore::Mongo => RateLimit::new(MongoStore),
+    };
+    limiter
+        .route(
+            Method::POST,
+            "/api/todos",
+            Limit::token_bucket(10, MINUTE).per_user(),
+        )
+        // each of these runs an argon2 hash
+        .route(
+            Method::POST,
+            "/api/planetscale/users",
+            Limit::sliding_window(5, MINUTE),
+        )
+        .route(
+            Method::POST,
+            "/api/planetscale/auth/login",
+            Limit::sliding_window(10, MINUTE),
+        )
+        .route(
+            Method::PUT,
+            "/api/planetscale/users/{id}/password",
+            Limit::sliding_window(5, MINUTE).per_user(),
+        )
+        .route(
+            Method::POST,
+            "/api/planetscale/auth/password-reset",
+            Limit::sliding_window(3, MINUTE),
+        )
+        .route(
+            Method::POST,
+            "/api/planetscale/auth/password-reset/confirm",
+            Limit::sliding_window(5, MINUTE),
+        )
+}
+
+pub async fn run() -> anyhow::Result<(), lambda_http::Error> {
+    // indexes are managed by the `migrate` binary at deploy time, not on every cold start
+    let env = Env::global()?;
+    let subscriber = FmtSubscriber::builder()
+        .with_max_level(env.log_level)
+        .finish();
     tracing::subscriber::set_global_default(subscriber)?;
     // launch
-    let factory = move || App::new().service(scope("/api").configure(api::routes));
+    let api_keys = api_keys(env.rate_limit_store);
+    let rate_limit = rate_limit(env.rate_limit_store);
+    let notifier = web::Data::from(notify::from_env(env));
+    let token_versions = web::Data::from(Arc::new(UserTokenVersions) as Arc<dyn TokenVersions>);
+    let factory = move || {
+        let cors = env
+            .cors_origins
+            .iter()
+            .fold(Cors::default(), |cors, origin| match origin.as_str() {
+                "*" => cors.allow_any_origin(),
+                origin => cors.allowed_origin(origin),
+            })
+            .allow_any_method()
+            .allow_any_header()
+            .max_age(3600);
+        App::new()
+            // registered first so it runs inside the api key check and sees the key
+            .wrap(AuditActor)
+            .wrap(Condition::new(env.features.api_keys, api_keys.clone()))
+            .wrap(Condition::new(env.features.rate_limit, rate_limit.clone()))
+            .wrap(Condition::new(!env.cors_origins.is_empty(), cors))
+            .app_data(web::Data::new(env.clone()))
+            .app_data(notifier.clone())
+            .app_data(token_versions.clone())
+            .app_data(web::JsonConfig::default().error_handler(|err, _| {
+                ApiError::validation("body", err).into()
+            }))
+            .app_data(web::QueryConfig::default().error_handler(|err, _| {
+                ApiError::validation("query", err).into()
+            }))
+            .app_data(web::PathConfig::default().error_handler(|err, _| {
+                ApiError::validation("path", err).into()
+            }))
+            .service(scope("/api").configure(|cfg| api::routes(cfg, &env.features)))
+    };
     if is_running_on_lambda() {
         run_actix_on_lambda(factory).await?;
     } else {
         HttpServer::new(factory)
-            .bind(("0.0.0.0", 3000))?
+            .bind((env.host.as_str(), env.port))?
             .run()
             .await?;
     }